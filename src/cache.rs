@@ -0,0 +1,107 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use booru_db::ID;
+use fxhash::{FxHashMap, FxHashSet};
+
+struct Entry {
+    ids: Arc<Vec<ID>>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: FxHashMap<(u64, String), Entry>,
+    tick: u64,
+}
+
+// Caches the unsorted ids matched by a query so repeated searches skip `Db::query`.
+//
+// Every entry costs roughly `4 bytes * matched` so memory is bounded by
+// `capacity * max_ids`, results larger than `max_ids` are never cached.
+// Keys include the generation which is bumped on every sync mutation,
+// stale entries are never hit again and fall out through LRU eviction.
+pub struct QueryCache {
+    capacity: usize,
+    max_ids: usize,
+    generation: AtomicU64,
+    entries: Mutex<Entries>,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize, max_ids: usize) -> Self {
+        Self {
+            capacity,
+            max_ids,
+            generation: AtomicU64::new(0),
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `query` should be canonical, see `routes::query::canonical`.
+    pub fn key(&self, query: String) -> (u64, String) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        (generation, query)
+    }
+
+    pub fn get(&self, key: &(u64, String)) -> Option<Arc<Vec<ID>>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let entry = entries.map.get_mut(key)?;
+        entry.last_used = tick;
+        Some(entry.ids.clone())
+    }
+
+    pub fn accepts(&self, matched: usize) -> bool {
+        self.capacity > 0 && matched <= self.max_ids
+    }
+
+    pub fn insert(&self, key: (u64, String), ids: Arc<Vec<ID>>) {
+        if !self.accepts(ids.len()) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.len() >= self.capacity {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+        entries.tick += 1;
+        let last_used = entries.tick;
+        entries.map.insert(key, Entry { ids, last_used });
+    }
+}
+
+// Equivalent of `get_sorted` for cached ids.
+pub fn get_sorted(
+    ids: &[ID],
+    mut sort: impl DoubleEndedIterator<Item = ID>,
+    index: usize,
+    limit: usize,
+    reverse: bool,
+) -> Vec<ID> {
+    let ids: FxHashSet<ID> = ids.iter().copied().collect();
+    let page = |sort: &mut dyn Iterator<Item = ID>| -> Vec<ID> {
+        sort.filter(|id| ids.contains(id))
+            .skip(index)
+            .take(limit)
+            .collect()
+    };
+    if reverse {
+        page(&mut sort.rev())
+    } else {
+        page(&mut sort)
+    }
+}
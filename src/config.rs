@@ -0,0 +1,111 @@
+use std::{fmt::Display, str::FromStr};
+
+// Value following `flag` on the command line, eg. `--snapshot <path>`.
+pub fn arg_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+// `--flag <value>`, then the `env` variable, then `default`. Invalid values are fatal.
+fn setting<T: FromStr>(flag: &str, env: &str, default: T) -> T
+where
+    T::Err: Display,
+{
+    let Some(value) = arg_value(flag).or_else(|| std::env::var(env).ok()) else {
+        return default;
+    };
+    value
+        .parse()
+        .unwrap_or_else(|error| panic!("invalid {flag} {value:?}: {error}"))
+}
+
+// Limits tuned per deployment, read once at startup.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    // Number of query results kept in the cache, 0 disables it.
+    pub query_cache_capacity: usize,
+    // Larger `limit`s are clamped to these.
+    pub posts_max_limit: usize,
+    pub tags_max_limit: usize,
+    // Queries matching more than this fraction of all posts are rejected with 429 past
+    // this offset, unless `allow_full_scan` is set.
+    pub full_scan_fraction: f64,
+    pub full_scan_max_offset: usize,
+    // Most tags a wildcard like `cat*` expands to, the most used ones are kept.
+    pub tag_wildcard_limit: usize,
+    // Name searches on tags and users stop collecting matches past this many, so `*a*` style
+    // queries stay cheap. `/tags` reports `truncated` when it was hit.
+    pub name_query_limit: usize,
+    // `sort=rank` only ranks posts created this long before the newest post, and this much
+    // age is worth one order of magnitude (base 3) of score.
+    pub rank_window_seconds: i64,
+    pub rank_decay_seconds: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            query_cache_capacity: 256,
+            posts_max_limit: 200,
+            tags_max_limit: 200,
+            full_scan_fraction: 0.5,
+            full_scan_max_offset: 10_000,
+            tag_wildcard_limit: 1_000,
+            name_query_limit: 50_000,
+            rank_window_seconds: 2 * 24 * 60 * 60,
+            rank_decay_seconds: 35_000.0,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Self {
+        let default = Self::default();
+        Self {
+            query_cache_capacity: setting(
+                "--query-cache",
+                "BOORU_DB_QUERY_CACHE",
+                default.query_cache_capacity,
+            ),
+            posts_max_limit: setting(
+                "--posts-max-limit",
+                "BOORU_DB_POSTS_MAX_LIMIT",
+                default.posts_max_limit,
+            ),
+            tags_max_limit: setting(
+                "--tags-max-limit",
+                "BOORU_DB_TAGS_MAX_LIMIT",
+                default.tags_max_limit,
+            ),
+            full_scan_fraction: setting(
+                "--full-scan-fraction",
+                "BOORU_DB_FULL_SCAN_FRACTION",
+                default.full_scan_fraction,
+            ),
+            full_scan_max_offset: setting(
+                "--full-scan-max-offset",
+                "BOORU_DB_FULL_SCAN_MAX_OFFSET",
+                default.full_scan_max_offset,
+            ),
+            tag_wildcard_limit: setting(
+                "--tag-wildcard-limit",
+                "BOORU_DB_TAG_WILDCARD_LIMIT",
+                default.tag_wildcard_limit,
+            ),
+            name_query_limit: setting(
+                "--name-query-limit",
+                "BOORU_DB_NAME_QUERY_LIMIT",
+                default.name_query_limit,
+            ),
+            rank_window_seconds: setting(
+                "--rank-window-seconds",
+                "BOORU_DB_RANK_WINDOW_SECONDS",
+                default.rank_window_seconds,
+            ),
+            rank_decay_seconds: setting(
+                "--rank-decay-seconds",
+                "BOORU_DB_RANK_DECAY_SECONDS",
+                default.rank_decay_seconds,
+            ),
+        }
+    }
+}
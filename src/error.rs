@@ -0,0 +1,55 @@
+use std::{fmt, num::ParseIntError};
+
+// Why a query value failed to parse, reported back to the user with the offending term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    NotANumber,
+    UnknownKeyword,
+    UnknownUnit,
+    OutOfRange,
+    BadRange,
+    BadPattern,
+    TooBroad,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::NotANumber => "not a number",
+            Self::UnknownKeyword => "unknown keyword",
+            Self::UnknownUnit => "unknown unit",
+            Self::OutOfRange => "out of range",
+            Self::BadRange => "malformed range",
+            Self::BadPattern => "malformed pattern",
+            Self::TooBroad => "matches too many tags to negate",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl From<ParseIntError> for ParseError {
+    fn from(_: ParseIntError) -> Self {
+        Self::NotANumber
+    }
+}
+
+// A posts row with a column that doesn't fit the type it's indexed as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RowError {
+    pub post_id: i32,
+    pub field: &'static str,
+    pub value: String,
+    pub reason: ParseError,
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            post_id,
+            field,
+            value,
+            reason,
+        } = self;
+        write!(f, "post {post_id} {field} {value:?}: {reason}")
+    }
+}
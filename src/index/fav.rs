@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader, KeysIndex, KeysIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+use fxhash::FxHashMap;
+
+use crate::BooruPost;
+
+pub struct FavIndexLoader {
+    keys_loader: KeysIndexLoader<u32>,
+    any_loader: KeyIndexLoader<bool>,
+    user_ids: FxHashMap<Arc<str>, u32>,
+}
+
+impl FavIndexLoader {
+    pub fn new(names: &FxHashMap<u32, Arc<str>>) -> Self {
+        Self {
+            keys_loader: KeysIndexLoader::new(),
+            any_loader: KeyIndexLoader::new(),
+            user_ids: names
+                .iter()
+                .map(|(&user_id, name)| (name.clone(), user_id))
+                .collect(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for FavIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.keys_loader.add(id, post.favs.iter());
+        self.any_loader.add(id, &!post.favs.is_empty());
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(FavIndex {
+            keys_index: self.keys_loader.load(),
+            any_index: self.any_loader.load(),
+            user_ids: self.user_ids,
+        })
+    }
+}
+
+pub struct FavIndex {
+    keys_index: KeysIndex<u32>,
+    any_index: KeyIndex<bool>,
+    user_ids: FxHashMap<Arc<str>, u32>,
+}
+
+impl Index<BooruPost> for FavIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        // Users are looked up by name first, falling back to a numeric user id.
+        let queryable = match text {
+            "any" => self.any_index.get(&true),
+            "none" => self.any_index.get(&false),
+            _ => {
+                let user_id = match self.user_ids.get(text) {
+                    Some(&user_id) => user_id,
+                    None => text.parse().ok()?,
+                };
+                self.keys_index.get(&user_id)
+            }
+        }
+        // A user without favorites, `-fav:` then matches every post.
+        .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.insert(id, post.favs.iter());
+        self.any_index.insert(id, &!post.favs.is_empty());
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.remove(id, post.favs.iter());
+        self.any_index.remove(id, &!post.favs.is_empty());
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.favs == new.favs {
+            return;
+        }
+        self.keys_index.update(id, &old.favs, &new.favs);
+        self.any_index
+            .update(id, &!old.favs.is_empty(), &!new.favs.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::str::FromStr;
+
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+use fxhash::FxHashMap;
+
+use super::TryQuery;
+use crate::{error::ParseError, post::FileExt, BooruPost};
+
+// Groups of extensions, `is:animated` is `file_ext:animated` which ORs every animated extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileCategory {
+    Animated,
+    Flash,
+    Image,
+    Video,
+}
+
+impl FileCategory {
+    fn of(file_ext: &FileExt) -> &'static [Self] {
+        match file_ext {
+            FileExt::AVIF | FileExt::BMP | FileExt::JPG | FileExt::PNG | FileExt::WEBP => {
+                &[Self::Image]
+            }
+            FileExt::GIF => &[Self::Animated],
+            FileExt::MP4 | FileExt::WEBM => &[Self::Animated, Self::Video],
+            FileExt::SWF => &[Self::Animated, Self::Flash],
+            FileExt::ZIP | FileExt::Other(_) => &[],
+        }
+    }
+
+    fn file_exts(self) -> Vec<FileExt> {
+        FileExt::ALL
+            .into_iter()
+            .filter(|file_ext| Self::of(file_ext).contains(&self))
+            .collect()
+    }
+}
+
+impl FromStr for FileCategory {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("animated") => Ok(Self::Animated),
+            s if s.eq_ignore_ascii_case("flash") => Ok(Self::Flash),
+            s if s.eq_ignore_ascii_case("image") => Ok(Self::Image),
+            s if s.eq_ignore_ascii_case("video") => Ok(Self::Video),
+            _ => Err(ParseError::UnknownKeyword),
+        }
+    }
+}
+
+// Posts per extension not known to `FileExt`, so `file_ext:unknown` can OR all of them.
+#[derive(Default)]
+struct Others(FxHashMap<Box<str>, usize>);
+
+impl Others {
+    fn add(&mut self, file_ext: &FileExt) {
+        if let FileExt::Other(ext) = file_ext {
+            *self.0.entry(ext.clone()).or_default() += 1;
+        }
+    }
+
+    fn remove(&mut self, file_ext: &FileExt) {
+        let FileExt::Other(ext) = file_ext else {
+            return;
+        };
+        if let Some(count) = self.0.get_mut(ext) {
+            *count -= 1;
+            if *count == 0 {
+                self.0.remove(ext);
+            }
+        }
+    }
+}
+
+pub struct FileExtIndexLoader {
+    key_loader: KeyIndexLoader<FileExt>,
+    others: Others,
+}
+
+impl Default for FileExtIndexLoader {
+    fn default() -> Self {
+        Self {
+            key_loader: KeyIndexLoader::new(),
+            others: Others::default(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for FileExtIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.key_loader.add(id, &post.file_ext);
+        self.others.add(&post.file_ext);
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(FileExtIndex {
+            key_index: self.key_loader.load(),
+            others: self.others,
+        })
+    }
+}
+
+pub struct FileExtIndex {
+    key_index: KeyIndex<FileExt>,
+    others: Others,
+}
+
+impl FileExtIndex {
+    fn expand(&self, value: &str) -> Result<Vec<FileExt>, ParseError> {
+        if let Ok(category) = value.parse::<FileCategory>() {
+            return Ok(category.file_exts());
+        }
+        let file_exts = match value {
+            // Danbooru stores ugoira as zip.
+            "ugoira" => vec![FileExt::ZIP],
+            "unknown" => self.others.0.keys().cloned().map(FileExt::Other).collect(),
+            value => vec![value.parse()?],
+        };
+        Ok(file_exts)
+    }
+}
+
+impl TryQuery for FileExtIndex {
+    fn try_query<'s>(
+        &'s self,
+        text: &str,
+        inverse: bool,
+    ) -> Result<Query<Queryable<'s>>, ParseError> {
+        let mut or_chain = Vec::new();
+        for value in text.split(',') {
+            for file_ext in self.expand(value)? {
+                if let Some(queryable) = self.key_index.get(&file_ext) {
+                    or_chain.push(Query::new(Item::Single(queryable), false));
+                }
+            }
+        }
+        // A known extension no post has yet, so `-file_ext:value` still matches every post.
+        let item = match or_chain.is_empty() {
+            true => Item::Single(Queryable::IDsOwned(vec![])),
+            false => Item::OrChain(or_chain),
+        };
+        Ok(Query::new(item, inverse))
+    }
+}
+
+impl Index<BooruPost> for FileExtIndex {
+    fn query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.reported_query(ident, text, inverse)
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.insert(id, &post.file_ext);
+        self.others.add(&post.file_ext);
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.remove(id, &post.file_ext);
+        self.others.remove(&post.file_ext);
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.file_ext == new.file_ext {
+            return;
+        }
+        self.key_index.update(id, &old.file_ext, &new.file_ext);
+        self.others.remove(&old.file_ext);
+        self.others.add(&new.file_ext);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        post::{tests::post, FileExt},
+        tests::{db, search},
+        BooruPost,
+    };
+
+    fn with_ext(id: i32, file_ext: FileExt) -> BooruPost {
+        BooruPost {
+            file_ext,
+            ..post(id)
+        }
+    }
+
+    #[test]
+    fn categories_expand_to_file_exts() {
+        let db = db(vec![
+            with_ext(1, FileExt::JPG),
+            with_ext(2, FileExt::GIF),
+            with_ext(3, FileExt::MP4),
+            with_ext(4, FileExt::SWF),
+            with_ext(5, FileExt::ZIP),
+        ]);
+        assert_eq!(search(&db, "file_ext:animated"), [2, 3, 4]);
+        assert_eq!(search(&db, "file_ext:video"), [3]);
+        assert_eq!(search(&db, "file_ext:flash"), [4]);
+        assert_eq!(search(&db, "file_ext:image"), [1]);
+        assert_eq!(search(&db, "is:animated -is:video"), [2, 4]);
+    }
+}
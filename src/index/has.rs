@@ -0,0 +1,186 @@
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+use fxhash::FxHashMap;
+
+use super::id::PostIds;
+use crate::BooruPost;
+
+pub struct HasIndexLoader {
+    parent_loader: KeyIndexLoader<bool>,
+    notes_loader: KeyIndexLoader<bool>,
+    comments_loader: KeyIndexLoader<bool>,
+    post_ids: PostIds,
+    child_counts: FxHashMap<u32, u32>,
+}
+
+impl HasIndexLoader {
+    pub fn new(post_ids: PostIds) -> Self {
+        Self {
+            parent_loader: KeyIndexLoader::new(),
+            notes_loader: KeyIndexLoader::new(),
+            comments_loader: KeyIndexLoader::new(),
+            post_ids,
+            child_counts: FxHashMap::default(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for HasIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.parent_loader.add(id, &post.parent_id.is_some());
+        self.notes_loader.add(id, &post.last_noted_at.is_some());
+        self.comments_loader
+            .add(id, &post.last_commented_at.is_some());
+        if let Some(parent_id) = post.parent_id {
+            *self.child_counts.entry(parent_id).or_default() += 1;
+        }
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        let parent_index = self.parent_loader.load();
+
+        // Children can be loaded before their parent, so this is only known once every post was added.
+        let mut children_loader = KeyIndexLoader::new();
+        for (id, post_id) in self.post_ids.ids() {
+            children_loader.add(id, &self.child_counts.contains_key(&post_id));
+        }
+
+        Box::new(HasIndex {
+            parent_index,
+            notes_index: self.notes_loader.load(),
+            comments_index: self.comments_loader.load(),
+            children_index: children_loader.load(),
+            post_ids: self.post_ids,
+            child_counts: self.child_counts,
+        })
+    }
+}
+
+pub struct HasIndex {
+    parent_index: KeyIndex<bool>,
+    notes_index: KeyIndex<bool>,
+    comments_index: KeyIndex<bool>,
+    children_index: KeyIndex<bool>,
+    // Only ever used to find other posts, a post's own entry may not be written yet.
+    post_ids: PostIds,
+    // Number of posts pointing at each parent post id.
+    child_counts: FxHashMap<u32, u32>,
+}
+
+impl HasIndex {
+    fn add_child(&mut self, parent_id: u32) {
+        let count = self.child_counts.entry(parent_id).or_default();
+        *count += 1;
+        if *count == 1 {
+            if let Some(id) = self.post_ids.get(parent_id) {
+                self.children_index.update(id, &false, &true);
+            }
+        }
+    }
+
+    fn remove_child(&mut self, parent_id: u32) {
+        let Some(count) = self.child_counts.get_mut(&parent_id) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.child_counts.remove(&parent_id);
+            if let Some(id) = self.post_ids.get(parent_id) {
+                self.children_index.update(id, &true, &false);
+            }
+        }
+    }
+}
+
+impl Index<BooruPost> for HasIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        let queryable = match text {
+            "parent" => self.parent_index.get(&true),
+            "children" => self.children_index.get(&true),
+            "notes" => self.notes_index.get(&true),
+            "comments" => self.comments_index.get(&true),
+            _ => return None,
+        }
+        .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.parent_index.insert(id, &post.parent_id.is_some());
+        self.notes_index.insert(id, &post.last_noted_at.is_some());
+        self.comments_index
+            .insert(id, &post.last_commented_at.is_some());
+        self.children_index
+            .insert(id, &self.child_counts.contains_key(&post.id));
+        if let Some(parent_id) = post.parent_id {
+            self.add_child(parent_id);
+        }
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        if let Some(parent_id) = post.parent_id {
+            self.remove_child(parent_id);
+        }
+        self.parent_index.remove(id, &post.parent_id.is_some());
+        self.notes_index.remove(id, &post.last_noted_at.is_some());
+        self.comments_index
+            .remove(id, &post.last_commented_at.is_some());
+        self.children_index
+            .remove(id, &self.child_counts.contains_key(&post.id));
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.id == new.id
+            && old.parent_id == new.parent_id
+            && old.last_noted_at.is_some() == new.last_noted_at.is_some()
+            && old.last_commented_at.is_some() == new.last_commented_at.is_some()
+        {
+            return;
+        }
+        self.remove(id, old);
+        self.insert(id, new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        index::IdIndex,
+        post::tests::post,
+        tests::{db, search},
+        BooruPost,
+    };
+
+    fn child(id: i32, parent_id: u32) -> BooruPost {
+        BooruPost {
+            parent_id: Some(parent_id),
+            ..post(id)
+        }
+    }
+
+    #[test]
+    fn children_use_the_shared_post_ids() {
+        // The child comes first, so its parent's id isn't known while it's added.
+        let mut db = db(vec![child(2, 9), post(9), post(5)]);
+        assert_eq!(search(&db, "has:children"), [9]);
+
+        let new_child = child(7, 5);
+        let id = db.next_id();
+        db.insert(id, &new_child);
+        assert_eq!(search(&db, "has:children"), [5, 9]);
+
+        let id_index: &IdIndex = db.index().unwrap();
+        let id = id_index.post_id_to_id(2).unwrap();
+        db.remove(id, &child(2, 9));
+        assert_eq!(search(&db, "has:children"), [5]);
+        assert_eq!(search(&db, "-has:children"), [7, 9]);
+    }
+}
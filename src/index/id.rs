@@ -1,93 +1,189 @@
+use std::sync::{Arc, RwLock};
+
 use booru_db::{
     index::{Index, IndexLoader, RangeIndex, RangeIndexLoader},
     query::Item,
     Query, Queryable, ID,
 };
 
-use crate::BooruPost;
+use super::{range_error, TryQuery};
+use crate::{error::ParseError, BooruPost};
+
+// Post id to id lookup, only written by `IdIndex`. Indices referring to other posts by post id
+// get a clone instead of keeping their own map. Every loader has seen every post before the
+// first one is loaded, so the lookup is complete by then.
+#[derive(Clone, Default)]
+pub struct PostIds(Arc<RwLock<fxhash::FxHashMap<u32, ID>>>);
+
+impl PostIds {
+    pub fn get(&self, post_id: u32) -> Option<ID> {
+        self.0.read().unwrap().get(&post_id).copied()
+    }
+
+    // Every (id, post id) pair, ascending by id.
+    pub fn ids(&self) -> Vec<(ID, u32)> {
+        let mut ids: Vec<_> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&post_id, &id)| (id, post_id))
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn insert(&self, post_id: u32, id: ID) {
+        self.0.write().unwrap().insert(post_id, id);
+    }
+
+    fn remove(&self, post_id: u32) {
+        self.0.write().unwrap().remove(&post_id);
+    }
+}
 
-#[derive(Default)]
 pub struct IdIndexLoader {
-    post_id_to_id: fxhash::FxHashMap<u32, ID>,
+    post_ids: PostIds,
     range_index_loader: RangeIndexLoader<u32>,
 }
 
+impl IdIndexLoader {
+    pub fn new(post_ids: PostIds) -> Self {
+        Self {
+            post_ids,
+            range_index_loader: RangeIndexLoader::new(),
+        }
+    }
+}
+
 impl IndexLoader<BooruPost> for IdIndexLoader {
     fn add(&mut self, id: ID, post: &BooruPost) {
-        self.post_id_to_id.insert(post.id, id);
+        self.post_ids.insert(post.id, id);
         self.range_index_loader.add(id, post.id);
     }
 
     fn load(self: Box<Self>) -> Box<(dyn Index<BooruPost>)> {
         let index = IdIndex {
-            post_id_to_id: self.post_id_to_id,
+            post_ids: self.post_ids,
             range_index: self.range_index_loader.load(),
+            free_ids: Vec::new(),
         };
         Box::new(index)
     }
 }
 
 pub struct IdIndex {
-    post_id_to_id: fxhash::FxHashMap<u32, ID>,
+    post_ids: PostIds,
     pub range_index: RangeIndex<u32>,
+    // Ids of removed posts, handed out again by `take_free_id` so the id space stays as large
+    // as the most posts ever held at once. Sorts break ties on post ids rather than ids,
+    // so a reused id sorts wherever its new post belongs.
+    free_ids: Vec<ID>,
 }
 
 impl IdIndex {
+    // An id no post currently has, None if `Db::next_id` should be used instead.
+    pub fn take_free_id(&mut self) -> Option<ID> {
+        self.free_ids.pop()
+    }
+
+    pub fn free_ids(&self) -> usize {
+        self.free_ids.len()
+    }
+
     pub fn id_to_post_id(&self, id: ID) -> Option<u32> {
         self.range_index.id_values().get(&id).copied()
     }
 
     pub fn post_id_to_id(&self, post_id: u32) -> Option<ID> {
-        self.post_id_to_id.get(&post_id).copied()
+        self.post_ids.get(post_id)
     }
 }
 
-impl Index<BooruPost> for IdIndex {
-    fn query<'s>(
+impl TryQuery for IdIndex {
+    fn try_query<'s>(
         &'s self,
-        _ident: Option<&str>,
         text: &str,
         inverse: bool,
-    ) -> Option<Query<Queryable<'s>>> {
+    ) -> Result<Query<Queryable<'s>>, ParseError> {
+        // Every post, as the inverse of no posts.
+        if text == "any" {
+            let item = Item::Single(Queryable::IDsOwned(vec![]));
+            return Ok(Query::new(item, !inverse));
+        }
+        // Single ids and ranges can be mixed, eg. `100..200,500`.
         if text.contains(',') {
-            let ids: Vec<ID> = text
-                .split(',')
-                .filter_map(|v| {
-                    v.parse::<u32>()
-                        .ok()
-                        .and_then(|post_id| self.post_id_to_id(post_id))
-                })
-                .collect();
-            if ids.is_empty() {
-                return None;
+            let mut ids = Vec::new();
+            let mut or_chain = Vec::new();
+            for value in text.split(',') {
+                if let Ok(post_id) = value.parse::<u32>() {
+                    ids.extend(self.post_id_to_id(post_id));
+                } else {
+                    let range_query = value.parse().map_err(|_| range_error::<u32>(value))?;
+                    or_chain.push(self.range_index.get(range_query));
+                }
             }
-            let queryable = Queryable::IDsOwned(ids);
-            let item = Item::Single(queryable);
-            return Some(Query::new(item, inverse));
-        }
-        if let Ok(range_query) = text.parse() {
-            let mut query = self.range_index.get(range_query);
-            query.inverse = inverse;
-            return Some(query);
+            // Missing ids match no posts, like any other missing key.
+            let item = Item::Single(Queryable::IDsOwned(ids));
+            or_chain.push(Query::new(item, false));
+            return Ok(Query::new(Item::OrChain(or_chain), inverse));
         }
-        None
+        let range_query = text.parse().map_err(|_| range_error::<u32>(text))?;
+        let mut query = self.range_index.get(range_query);
+        query.inverse = inverse;
+        Ok(query)
+    }
+}
+
+impl Index<BooruPost> for IdIndex {
+    fn query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.reported_query(ident, text, inverse)
     }
 
     fn insert(&mut self, id: ID, post: &BooruPost) {
-        self.post_id_to_id.insert(post.id, id);
+        self.post_ids.insert(post.id, id);
         self.range_index.insert(id, post.id);
     }
 
     fn remove(&mut self, id: ID, post: &BooruPost) {
-        self.post_id_to_id.remove(&post.id);
+        self.post_ids.remove(post.id);
         self.range_index.remove(id, post.id);
+        self.free_ids.push(id);
     }
 
+    // The id stays in use, only the post id it maps to changes.
     fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
         if old.id == new.id {
             return;
         }
-        self.remove(id, old);
-        self.insert(id, new);
+        self.post_ids.remove(old.id);
+        self.post_ids.insert(new.id, id);
+        self.range_index.update(id, old.id, new.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        post::tests::post,
+        tests::{db, search},
+    };
+
+    #[test]
+    fn id_ranges_and_lists() {
+        let db = db((1..=6).map(post).collect());
+        assert_eq!(search(&db, "id:>4"), [5, 6]);
+        assert_eq!(search(&db, "id:<3"), [1, 2]);
+        assert_eq!(search(&db, "id:2..4"), [2, 3, 4]);
+        assert_eq!(search(&db, "id:..2"), [1, 2]);
+        assert_eq!(search(&db, "id:5,1,3"), [1, 3, 5]);
+        assert_eq!(search(&db, "id:1,5..,99"), [1, 5, 6]);
+        assert_eq!(search(&db, "-id:1,2..5"), [6]);
+        assert!(search(&db, "id:98,99").is_empty());
     }
 }
@@ -0,0 +1,66 @@
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+
+use crate::BooruPost;
+
+// `is:animated` style file categories are rewritten to `file_ext:` queries before reaching the Db,
+// see `FileCategory`.
+pub struct IsIndexLoader {
+    note_locked: KeyIndexLoader<bool>,
+}
+
+impl Default for IsIndexLoader {
+    fn default() -> Self {
+        Self {
+            note_locked: KeyIndexLoader::new(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for IsIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.note_locked.add(id, &post.is_note_locked);
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(IsIndex {
+            note_locked: self.note_locked.load(),
+        })
+    }
+}
+
+pub struct IsIndex {
+    note_locked: KeyIndex<bool>,
+}
+
+impl Index<BooruPost> for IsIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        let queryable = match text {
+            "note_locked" => self.note_locked.get(&true),
+            _ => return None,
+        }
+        .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.note_locked.insert(id, &post.is_note_locked);
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.note_locked.remove(id, &post.is_note_locked);
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        self.note_locked
+            .update(id, &old.is_note_locked, &new.is_note_locked);
+    }
+}
@@ -1,20 +1,112 @@
-use std::str::FromStr;
+use std::{cell::RefCell, cmp::Ordering, str::FromStr};
 
-use crate::post::{BooruPost, FileExt, Rating, Status};
+use booru_db::{Query, Queryable, ID};
+
+use crate::{
+    error::ParseError,
+    post::{BooruPost, Status},
+};
 
 // mod comment;
 // pub use comment::{Comment, CommentIndex};
+mod fav;
+pub use fav::{FavIndex, FavIndexLoader};
+mod file_ext;
+pub use file_ext::{FileCategory, FileExtIndex, FileExtIndexLoader};
+mod has;
+pub use has::{HasIndex, HasIndexLoader};
 mod id;
-pub use id::{IdIndex, IdIndexLoader};
-// mod pool;
-// pub use pool::{Pool, PoolCategory, PoolIndex};
+pub use id::{IdIndex, IdIndexLoader, PostIds};
+mod is;
+pub use is::{IsIndex, IsIndexLoader};
+mod name;
+pub use name::take_truncated;
+mod parallel;
+pub use parallel::ParallelDbLoader;
+mod parent;
+pub use parent::{ParentIdIndex, ParentIdIndexLoader};
+mod pool;
+pub use pool::{PoolIndex, PoolIndexLoader};
+mod post;
+pub use post::{PostIndex, PostIndexLoader};
+mod rating;
+pub use rating::{RatingIndex, RatingIndexLoader};
+mod source;
+pub use source::{SourceIndex, SourceIndexLoader};
 mod tag;
 pub use tag::{TagDbCountIndex, TagDbIdIndex, TagIndex, TagIndexLoader};
-// mod user;
-// pub use user::{UserIndex, UserIndexLoader};
+mod user;
+pub use user::{UserDbCountIndex, UserDbIdIndex, UserIndex, UserIndexLoader};
+
+// Every id in `queryable`. For reading an index's own entries, eg. the posts of a tag, where
+// building a query from the key would parse it as query syntax.
+pub fn queryable_ids(queryable: &Queryable) -> Vec<ID> {
+    queryable.get(0, queryable.matched(), false)
+}
+
+thread_local! {
+    static QUERY_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// The first term an index rejected on this thread since the last call and why,
+// eg. `rating:x: unknown keyword`. Like `take_truncated` this is read right after `Db::query`.
+pub fn take_query_error() -> Option<String> {
+    QUERY_ERROR.with(|error| error.take())
+}
+
+// `Index::query` that says why `text` is rejected. `Index::query` can only return None,
+// so it forwards to `reported_query` which keeps the error for `take_query_error`.
+pub trait TryQuery {
+    fn try_query<'s>(
+        &'s self,
+        text: &str,
+        inverse: bool,
+    ) -> Result<Query<Queryable<'s>>, ParseError>;
+
+    fn reported_query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.try_query(text, inverse)
+            .map_err(|error| {
+                let sign = if inverse { "-" } else { "" };
+                let term = match ident {
+                    Some(ident) => format!("{sign}{ident}:{text}"),
+                    None => format!("{sign}{text}"),
+                };
+                QUERY_ERROR.with(|first| {
+                    first.borrow_mut().get_or_insert(format!("{term}: {error}"));
+                });
+            })
+            .ok()
+    }
+}
 
+// Why `text` isn't a range of `V`, for values `RangeQuery` rejects.
+fn range_error<V>(text: &str) -> ParseError
+where
+    V: FromStr,
+    V::Err: Into<ParseError>,
+{
+    let bounds: Vec<_> = text
+        .trim_start_matches(['<', '>', '='])
+        .split("..")
+        .collect();
+    if bounds.len() > 2 {
+        return ParseError::BadRange;
+    }
+    bounds
+        .into_iter()
+        .filter(|bound| !bound.is_empty())
+        .find_map(|bound| bound.parse::<V>().err())
+        .map_or(ParseError::BadRange, Into::into)
+}
+
+// The optional last argument maps a query value to a comma separated list of values it stands for.
 macro_rules! key_index {
-    ($loader_name:ident, $index_name:ident, $key_type:ty, $get_key:expr) => {
+    ($loader_name:ident, $index_name:ident, $key_type:ty, $get_key:expr $(, $aliases:expr)?) => {
         pub struct $loader_name {
             key_loader: ::booru_db::index::KeyIndexLoader<$key_type>,
         }
@@ -46,39 +138,51 @@ macro_rules! key_index {
             key_index: ::booru_db::index::KeyIndex<$key_type>,
         }
 
+        #[allow(clippy::redundant_closure_call)]
+        impl TryQuery for $index_name {
+            fn try_query<'s>(
+                &'s self,
+                text: &::std::primitive::str,
+                inverse: ::std::primitive::bool,
+            ) -> ::std::result::Result<::booru_db::Query<::booru_db::Queryable<'s>>, ParseError> {
+                $(
+                    let expanded = text
+                        .split(',')
+                        .map(|value| $aliases(value).unwrap_or(value))
+                        .collect::<::std::vec::Vec<_>>()
+                        .join(",");
+                    let text = expanded.as_str();
+                )?
+                let mut or_chain = ::std::vec::Vec::new();
+                for value in text.split(',') {
+                    let key: $key_type = value.parse().map_err(::std::convert::Into::into)?;
+                    // A known value no post has yet matches nothing, so `-key:value` still
+                    // matches every post.
+                    if let ::std::option::Option::Some(queryable) = self.key_index.get(&key) {
+                        let item = ::booru_db::query::Item::Single(queryable);
+                        or_chain.push(::booru_db::Query::new(item, false));
+                    }
+                }
+                let item = match or_chain.len() {
+                    0 => ::booru_db::query::Item::Single(::booru_db::Queryable::IDsOwned(
+                        ::std::vec::Vec::new(),
+                    )),
+                    1 => or_chain.pop().unwrap().item,
+                    _ => ::booru_db::query::Item::OrChain(or_chain),
+                };
+                ::std::result::Result::Ok(::booru_db::Query::new(item, inverse))
+            }
+        }
+
         #[allow(clippy::redundant_closure_call)]
         impl ::booru_db::index::Index<BooruPost> for $index_name {
             fn query<'s>(
                 &'s self,
-                _ident: std::option::Option<&::std::primitive::str>,
+                ident: std::option::Option<&::std::primitive::str>,
                 text: &::std::primitive::str,
                 inverse: ::std::primitive::bool,
             ) -> ::std::option::Option<::booru_db::Query<::booru_db::Queryable<'s>>> {
-                if text.contains(',') {
-                    let mut or_chain = ::std::vec::Vec::new();
-                    for value in text.split(',') {
-                        if let ::std::result::Result::Ok(key) = value.parse() {
-                            if let ::std::option::Option::Some(queryable) = self.key_index.get(&key)
-                            {
-                                let item = ::booru_db::query::Item::Single(queryable);
-                                or_chain.push(::booru_db::Query::new(item, false));
-                            }
-                        }
-                    }
-                    if or_chain.is_empty() {
-                        return ::std::option::Option::None;
-                    }
-                    let item = ::booru_db::query::Item::OrChain(or_chain);
-                    return ::std::option::Option::Some(::booru_db::Query::new(item, inverse));
-                }
-                if let ::std::result::Result::Ok(key) = text.parse() {
-                    let queryable = self.key_index.get(&key)?;
-                    let item = ::booru_db::query::Item::Single(queryable);
-                    return ::std::option::Option::Some(::booru_db::query::Query::new(
-                        item, inverse,
-                    ));
-                }
-                ::std::option::Option::None
+                self.reported_query(ident, text, inverse)
             }
 
             fn insert(&mut self, id: ::booru_db::ID, post: &BooruPost) {
@@ -100,8 +204,11 @@ macro_rules! key_index {
     };
 }
 
+// The optional last argument maps a query value to the range it stands for, eg. `any` to `>none`.
 macro_rules! range_index {
-    ($loader_name:ident, $index_name:ident, $value_type:ty, $get_value:expr) => {
+    (
+        $loader_name:ident, $index_name:ident, $value_type:ty, $get_value:expr $(, $aliases:expr)?
+    ) => {
         pub struct $loader_name {
             range_loader: ::booru_db::index::RangeIndexLoader<$value_type>,
         }
@@ -133,20 +240,32 @@ macro_rules! range_index {
             pub range_index: ::booru_db::index::RangeIndex<$value_type>,
         }
 
+        #[allow(clippy::redundant_closure_call)]
+        impl TryQuery for $index_name {
+            fn try_query<'s>(
+                &'s self,
+                text: &::std::primitive::str,
+                inverse: ::std::primitive::bool,
+            ) -> ::std::result::Result<::booru_db::Query<::booru_db::Queryable<'s>>, ParseError> {
+                $(
+                    let text = $aliases(text).unwrap_or(text);
+                )?
+                let range_query = text.parse().map_err(|_| range_error::<$value_type>(text))?;
+                let mut query = self.range_index.get(range_query);
+                query.inverse = inverse;
+                ::std::result::Result::Ok(query)
+            }
+        }
+
         #[allow(clippy::redundant_closure_call)]
         impl ::booru_db::index::Index<BooruPost> for $index_name {
             fn query<'s>(
                 &'s self,
-                _ident: std::option::Option<&::std::primitive::str>,
+                ident: std::option::Option<&::std::primitive::str>,
                 text: &::std::primitive::str,
                 inverse: ::std::primitive::bool,
             ) -> ::std::option::Option<::booru_db::Query<::booru_db::Queryable<'s>>> {
-                if let ::std::result::Result::Ok(range_query) = text.parse() {
-                    let mut query = self.range_index.get(range_query);
-                    query.inverse = inverse;
-                    return ::std::option::Option::Some(query);
-                }
-                ::std::option::Option::None
+                self.reported_query(ident, text, inverse)
             }
 
             fn insert(&mut self, id: ::booru_db::ID, post: &BooruPost) {
@@ -168,36 +287,18 @@ macro_rules! range_index {
     };
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
-pub struct ParentId(Option<u32>);
-impl FromStr for ParentId {
-    type Err = ();
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s == "none" {
-            return Ok(ParentId(None));
-        }
-        s.parse::<u32>().map(|i| Self(Some(i))).map_err(|_| ())
-    }
-}
-#[rustfmt::skip]
-key_index!(
-    ParentIdIndexLoader,
-    ParentIdIndex,
-    ParentId,
-    |p: &BooruPost| ParentId(p.parent_id)
-);
-
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PixivId(Option<u32>);
 impl FromStr for PixivId {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "none" {
             return Ok(PixivId(None));
         }
-        s.parse::<u32>().map(|i| Self(Some(i))).map_err(|_| ())
+        s.parse::<u32>()
+            .map(|i| Self(Some(i)))
+            .map_err(|_| ParseError::NotANumber)
     }
 }
 #[rustfmt::skip]
@@ -205,7 +306,9 @@ range_index!(
     PixivIdIndexLoader,
     PixivIdIndex,
     PixivId,
-    |p: &BooruPost| PixivId(p.pixiv_id)
+    |p: &BooruPost| PixivId(p.pixiv_id),
+    // `none` sorts before every id, so `any` is everything above it.
+    |text: &str| (text == "any").then_some(">none")
 );
 
 #[rustfmt::skip]
@@ -219,13 +322,15 @@ key_index!(
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct ApproverId(Option<u32>);
 impl FromStr for ApproverId {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "none" {
             return Ok(ApproverId(None));
         }
-        s.parse::<u32>().map(|i| Self(Some(i))).map_err(|_| ())
+        s.parse::<u32>()
+            .map(|i| Self(Some(i)))
+            .map_err(|_| ParseError::NotANumber)
     }
 }
 #[rustfmt::skip]
@@ -260,6 +365,72 @@ range_index!(
     |p: &BooruPost| p.updated_at.timestamp_millis()
 );
 
+// Posts never commented on or noted get `i64::MIN`, last when sorting newest first.
+#[rustfmt::skip]
+range_index!(
+    CommentAtIndexLoader,
+    CommentAtIndex,
+    i64,
+    |p: &BooruPost| p.last_commented_at.map_or(i64::MIN, |at| at.timestamp_millis())
+);
+
+#[rustfmt::skip]
+range_index!(
+    NoteAtIndexLoader,
+    NoteAtIndex,
+    i64,
+    |p: &BooruPost| p.last_noted_at.map_or(i64::MIN, |at| at.timestamp_millis())
+);
+
+// Multiplier of a number's unit suffix, matched ignoring case.
+fn unit<T: Copy>(suffix: &str, units: &[(&[&str], T)]) -> Result<T, ParseError> {
+    units
+        .iter()
+        .find(|(names, _)| names.iter().any(|name| name.eq_ignore_ascii_case(suffix)))
+        .map(|&(_, multiplier)| multiplier)
+        .ok_or(ParseError::UnknownUnit)
+}
+
+// Milliseconds, parsed from a number with a unit like danbooru's `age:`, eg. `12h`, `1w` or `2mo`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EditDelay(i64);
+
+impl FromStr for EditDelay {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const SECOND: i64 = 1000;
+        const DAY: i64 = 24 * 60 * 60 * SECOND;
+        let number = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier = unit(
+            &s[number.len()..],
+            &[
+                (&["s", "sec", "second", "seconds"], SECOND),
+                (&["mi", "min", "minute", "minutes"], 60 * SECOND),
+                (&["h", "hour", "hours"], 60 * 60 * SECOND),
+                (&["d", "day", "days"], DAY),
+                (&["w", "week", "weeks"], 7 * DAY),
+                (&["mo", "month", "months"], 30 * DAY),
+                (&["y", "year", "years"], 365 * DAY),
+            ],
+        )?;
+        let number: i64 = number.parse().map_err(|_| ParseError::NotANumber)?;
+        number
+            .checked_mul(multiplier)
+            .map(EditDelay)
+            .ok_or(ParseError::OutOfRange)
+    }
+}
+
+// Time between upload and the last edit, clamped to 0 where clocks disagreed.
+#[rustfmt::skip]
+range_index!(
+    EditDelayIndexLoader,
+    EditDelayIndex,
+    EditDelay,
+    |p: &BooruPost| EditDelay((p.updated_at - p.created_at).num_milliseconds().max(0))
+);
+
 #[rustfmt::skip]
 range_index!(
     FavCountIndexLoader,
@@ -292,6 +463,49 @@ range_index!(
     |p: &BooruPost| p.down_score
 );
 
+// `pop:` is `POPULARITY_FAV_WEIGHT * favcount + POPULARITY_SCORE_WEIGHT * score`.
+const POPULARITY_FAV_WEIGHT: i32 = 1;
+const POPULARITY_SCORE_WEIGHT: i32 = 1;
+
+#[rustfmt::skip]
+range_index!(
+    PopularityIndexLoader,
+    PopularityIndex,
+    i32,
+    |p: &BooruPost| {
+        let fav = (p.fav_count as i32).saturating_mul(POPULARITY_FAV_WEIGHT);
+        let score = (p.up_score + p.down_score).saturating_mul(POPULARITY_SCORE_WEIGHT);
+        fav.saturating_add(score)
+    }
+);
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UpRatio(u32);
+
+impl FromStr for UpRatio {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ratio: f32 = s.parse().map_err(|_| ParseError::NotANumber)?;
+        Ok(UpRatio((ratio.clamp(0.0, 1.0) * 1_000.0) as u32))
+    }
+}
+
+#[rustfmt::skip]
+range_index!(
+    UpRatioIndexLoader,
+    UpRatioIndex,
+    UpRatio,
+    |p: &BooruPost| {
+        // Posts without votes have a ratio of 0.
+        let votes = p.up_score as i64 + (p.down_score as i64).abs();
+        if votes == 0 {
+            return UpRatio(0);
+        }
+        UpRatio((p.up_score.max(0) as f32 / votes as f32 * 1_000.0) as u32)
+    }
+);
+
 #[rustfmt::skip]
 range_index!(
     WidthIndexLoader,
@@ -308,19 +522,58 @@ range_index!(
     |p: &BooruPost| p.height
 );
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+    Square,
+}
+
+impl Orientation {
+    fn of(width: u16, height: u16) -> Self {
+        match width.cmp(&height) {
+            Ordering::Greater => Self::Landscape,
+            Ordering::Less => Self::Portrait,
+            Ordering::Equal => Self::Square,
+        }
+    }
+}
+
+impl FromStr for Orientation {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("landscape") => Ok(Self::Landscape),
+            s if s.eq_ignore_ascii_case("portrait") => Ok(Self::Portrait),
+            s if s.eq_ignore_ascii_case("square") => Ok(Self::Square),
+            _ => Err(ParseError::UnknownKeyword),
+        }
+    }
+}
+
+#[rustfmt::skip]
+key_index!(
+    OrientationIndexLoader,
+    OrientationIndex,
+    Orientation,
+    |p: &BooruPost| Orientation::of(p.width, p.height)
+);
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AspectRatio(u32);
 
 impl FromStr for AspectRatio {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let ratio = if let Some((a, b)) = s.split_once('/') {
-            let a: f32 = a.parse().map_err(|_| ())?;
-            let b: f32 = b.parse().map_err(|_| ())?;
+        // Range bounds are split on `..` before reaching here so `16:9..21:9` parses each side.
+        let ratio = if let Some((a, b)) = s.split_once(['/', ':']) {
+            let a: f32 = a.parse().map_err(|_| ParseError::NotANumber)?;
+            let b: f32 = b.parse().map_err(|_| ParseError::NotANumber)?;
             AspectRatio((a / b * 1_000.0) as u32)
         } else {
-            let ratio: f32 = s.parse().map_err(|_| ())?;
+            let ratio: f32 = s.parse().map_err(|_| ParseError::NotANumber)?;
             AspectRatio((ratio * 1_000.0) as u32)
         };
         Ok(ratio)
@@ -332,18 +585,25 @@ range_index!(
     AspectRatioIndexLoader,
     AspectRatioIndex,
     AspectRatio,
-    |p: &BooruPost| AspectRatio((p.width as f32 / p.height as f32 * 1_000.0) as u32)
+    |p: &BooruPost| {
+        // Corrupt rows can have a zero height, keep them at the bottom instead of at u32::MAX.
+        if p.height == 0 {
+            return AspectRatio(0);
+        }
+        AspectRatio((p.width as f32 / p.height as f32 * 1_000.0) as u32)
+    }
 );
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MPixel(u32);
 
 impl FromStr for MPixel {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut float: f32 = s.parse().map_err(|_| ())?;
-        float = float.clamp(0.0, 1000.0);
+        let float: f64 = s.parse().map_err(|_| ParseError::NotANumber)?;
+        // Float to int casts saturate, so anything past u32::MAX pixels still compares above every
+        // stored value (at most 65535 * 65535) and negatives become 0.
         Ok(MPixel((float * 1_000_000.0) as u32))
     }
 }
@@ -356,28 +616,40 @@ range_index!(
     |p: &BooruPost| MPixel(p.width as u32 * p.height as u32)
 );
 
-#[rustfmt::skip]
-key_index!(
-    FileExtIndexLoader,
-    FileExtIndex,
-    FileExt,
-    |p: &BooruPost| p.file_ext
-);
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileSize(pub u32);
+
+// Bytes with an optional binary unit like danbooru, eg. `5mb`, `500kb` or `1.5gb`.
+impl FromStr for FileSize {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier = unit(
+            &s[number.len()..],
+            &[
+                (&["", "b"], 1.0),
+                (&["k", "kb"], 1024.0),
+                (&["m", "mb"], 1024.0 * 1024.0),
+                (&["g", "gb"], 1024.0 * 1024.0 * 1024.0),
+            ],
+        )?;
+        let float: f64 = number.parse().map_err(|_| ParseError::NotANumber)?;
+        let bytes = float * multiplier;
+        // Stored sizes are u32, values past 4GiB can't match anything and are rejected.
+        if !(0.0..=u32::MAX as f64).contains(&bytes) {
+            return Err(ParseError::OutOfRange);
+        }
+        Ok(FileSize(bytes as u32))
+    }
+}
 
 #[rustfmt::skip]
 range_index!(
     FileSizeIndexLoader,
     FileSizeIndex,
-    u32,
-    |p: &BooruPost| p.file_size
-);
-
-#[rustfmt::skip]
-key_index!(
-    RatingIndexLoader,
-    RatingIndex,
-    Rating,
-    |p: &BooruPost| p.rating
+    FileSize,
+    |p: &BooruPost| FileSize(p.file_size)
 );
 
 #[rustfmt::skip]
@@ -388,6 +660,21 @@ range_index!(
     |p: &BooruPost| p.tags.len() as u16
 );
 
+// Sum of the per category columns, which can disagree with `tags.len()` when upstream counts are stale.
+#[rustfmt::skip]
+range_index!(
+    DbTagCountIndexLoader,
+    DbTagCountIndex,
+    u16,
+    |p: &BooruPost| {
+        p.tag_count_general
+            .saturating_add(p.tag_count_artist)
+            .saturating_add(p.tag_count_character)
+            .saturating_add(p.tag_count_copyright)
+            .saturating_add(p.tag_count_meta)
+    }
+);
+
 #[rustfmt::skip]
 range_index!(
     TagCountGeneralIndexLoader,
@@ -427,3 +714,204 @@ range_index!(
     u16,
     |p: &BooruPost| p.tag_count_meta
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        post::tests::post,
+        tests::{db, search},
+    };
+
+    fn sized(id: i32, width: u16, height: u16) -> BooruPost {
+        BooruPost {
+            width,
+            height,
+            ..post(id)
+        }
+    }
+
+    #[test]
+    fn zero_height_ratio_is_lowest() {
+        let db = db(vec![
+            sized(1, 1000, 1000),
+            sized(2, 1600, 900),
+            sized(3, 500, 0),
+        ]);
+        assert_eq!(search(&db, "ratio:0"), [3]);
+        assert_eq!(search(&db, "ratio:<0.5"), [3]);
+        assert_eq!(search(&db, "ratio:>1"), [2]);
+        assert_eq!(search(&db, "ratio:16:9"), [2]);
+        let index: &AspectRatioIndex = db.index().unwrap();
+        let lowest = index.range_index.ids()[0];
+        let id_index: &IdIndex = db.index().unwrap();
+        assert_eq!(id_index.id_to_post_id(lowest), Some(3));
+    }
+
+    #[test]
+    fn inverse_is_the_complement() {
+        let db = db(vec![
+            BooruPost {
+                status: Status::Deleted,
+                up_score: 5,
+                pools: vec![1],
+                tags: vec!["a".into(), "missing_tag_not".into()],
+                ..post(1)
+            },
+            BooruPost {
+                tags: vec!["b".into()],
+                source: "https://example.com/b.png".into(),
+                ..post(2)
+            },
+            post(3),
+        ]);
+        let all = search(&db, "id:any");
+        assert_eq!(all, [1, 2, 3]);
+        let values = [
+            "status:deleted",
+            "status:flagged",
+            "status:deleted,flagged",
+            "status:flagged,pending",
+            "score:5",
+            "score:>100",
+            "rating:g",
+            "rating:e",
+            "rating:q,e",
+            "pool:1",
+            "pool:999",
+            "fav:999",
+            "user:nobody",
+            "a",
+            "missing_tag",
+            "/mtn",
+            "/xyz",
+            "source:example.com",
+            "source:newsite.com",
+            "source:pixiv",
+        ];
+        for text in values {
+            let matched = search(&db, text);
+            let inverse = search(&db, &format!("-{text}"));
+            let mut union = [matched.clone(), inverse.clone()].concat();
+            union.sort_unstable();
+            assert_eq!(union, all, "{text} {matched:?} -{text} {inverse:?}");
+        }
+        for missing in [
+            "status:flagged,pending",
+            "rating:q,e",
+            "pool:999",
+            "user:nobody",
+            "/xyz",
+            "source:newsite.com",
+        ] {
+            assert_eq!(search(&db, missing), [] as [u32; 0], "{missing}");
+        }
+        assert_eq!(search(&db, "/mtn"), [1]);
+    }
+
+    #[test]
+    fn range_forms() {
+        let valued = |id: i32, value: u16| BooruPost {
+            up_score: value.into(),
+            width: value,
+            fav_count: value.into(),
+            tags: (0..value).map(|i| i.to_string().into()).collect(),
+            ..post(id)
+        };
+        let db = db(vec![valued(1, 1), valued(2, 5), valued(3, 10)]);
+        for ident in ["score", "width", "favcount", "tagcount"] {
+            let search = |value: &str| search(&db, &format!("{ident}:{value}"));
+            assert_eq!(search("5"), [2], "{ident}");
+            assert_eq!(search("5.."), [2, 3], "{ident}");
+            assert_eq!(search("..5"), [1, 2], "{ident}");
+            assert_eq!(search("2..10"), [2, 3], "{ident}");
+            assert_eq!(search(">5"), [3], "{ident}");
+            assert_eq!(search("<5"), [1], "{ident}");
+            let inverse = |value: &str| crate::tests::search(&db, &format!("-{ident}:{value}"));
+            assert_eq!(inverse("5"), [1, 3], "{ident}");
+            assert_eq!(inverse("5.."), [1], "{ident}");
+            assert_eq!(inverse("..5"), [3], "{ident}");
+        }
+    }
+
+    #[test]
+    fn largest_mpixel_isnt_clamped() {
+        let db = db(vec![
+            sized(1, 1000, 1000),
+            sized(2, 4000, 3000),
+            sized(3, u16::MAX, u16::MAX),
+        ]);
+        assert_eq!(search(&db, "mpixel:>1000"), [3]);
+        assert_eq!(search(&db, "mpixel:>4294"), [3]);
+        assert_eq!(search(&db, "mpixel:>4295"), [] as [u32; 0]);
+        assert_eq!(search(&db, "mpixel:<1e12"), [1, 2, 3]);
+        assert_eq!(search(&db, "mpixel:1..12"), [1, 2]);
+        assert_eq!("1e12".parse(), Ok(MPixel(u32::MAX)));
+        assert_eq!("-1".parse(), Ok(MPixel(0)));
+    }
+
+    #[test]
+    fn dbtagcount_sums_category_columns() {
+        let stale = BooruPost {
+            tags: vec!["a".into(), "b".into()],
+            tag_count_general: 4,
+            tag_count_meta: 1,
+            ..post(1)
+        };
+        let fresh = BooruPost {
+            tags: vec!["a".into(), "b".into(), "c".into()],
+            tag_count_general: 1,
+            tag_count_artist: 2,
+            ..post(2)
+        };
+        let saturated = BooruPost {
+            tag_count_general: u16::MAX,
+            tag_count_artist: 1,
+            ..post(3)
+        };
+        let db = db(vec![stale, fresh, saturated]);
+        assert_eq!(search(&db, "tagcount:2"), [1]);
+        assert_eq!(search(&db, "dbtagcount:2"), [] as [u32; 0]);
+        assert_eq!(search(&db, "dbtagcount:5"), [1]);
+        assert_eq!(search(&db, "tagcount:3"), [2]);
+        assert_eq!(search(&db, "dbtagcount:3"), [2]);
+        assert_eq!(search(&db, "dbtagcount:>10"), [3]);
+    }
+
+    #[test]
+    fn units_ignore_ascii_case() {
+        assert_eq!("5MB".parse(), Ok(FileSize(5 * 1024 * 1024)));
+        assert_eq!("5Mb".parse(), Ok(FileSize(5 * 1024 * 1024)));
+        assert_eq!("1.5kB".parse(), Ok(FileSize(1536)));
+        assert_eq!("2H".parse(), Ok(EditDelay(2 * 60 * 60 * 1000)));
+        assert_eq!("1Week".parse(), Ok(EditDelay(7 * 24 * 60 * 60 * 1000)));
+        assert_eq!("5ｍｂ".parse::<FileSize>(), Err(ParseError::NotANumber));
+        assert_eq!("5µb".parse::<FileSize>(), Err(ParseError::NotANumber));
+        assert_eq!(
+            "1weeks\u{212a}".parse::<EditDelay>(),
+            Err(ParseError::UnknownUnit)
+        );
+        assert_eq!("5tb".parse::<FileSize>(), Err(ParseError::UnknownUnit));
+    }
+
+    #[test]
+    fn pixiv_id_none_any_and_numbers() {
+        let with_pixiv_id = |id: i32, pixiv_id: Option<u32>| BooruPost {
+            pixiv_id,
+            ..post(id)
+        };
+        let db = db(vec![
+            with_pixiv_id(1, None),
+            with_pixiv_id(2, Some(5)),
+            with_pixiv_id(3, Some(700)),
+            with_pixiv_id(4, None),
+        ]);
+        assert_eq!(search(&db, "pixiv_id:none"), [1, 4]);
+        assert_eq!(search(&db, "pixiv_id:any"), [2, 3]);
+        assert_eq!(search(&db, "-pixiv_id:any"), [1, 4]);
+        assert_eq!(search(&db, "pixiv_id:700"), [3]);
+        assert_eq!(search(&db, "pixiv_id:>5"), [3]);
+        assert_eq!(search(&db, "pixiv_id:1..100"), [2]);
+        assert!(search(&db, "pixiv_id:6").is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+use std::{cell::Cell, collections::hash_map::Entry, marker::PhantomData, sync::Arc};
+
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader},
+    query::Item,
+    Query, Queryable, TextQuery, ID,
+};
+use fxhash::FxHashMap;
+use rayon::prelude::*;
+
+pub trait Named {
+    fn name(&self) -> &Arc<str>;
+}
+
+// First character of every word, a word being a run of letters or a run of digits, so
+// `hatsune_miku_(vocaloid)` -> `hmv`, `re:zero` -> `rz` and `2girls` -> `2g`.
+pub fn abbreviate(text: &str) -> String {
+    let mut abbreviation = String::new();
+    // Whether the previous character was a digit, None after punctuation.
+    let mut previous = None;
+    for c in text.chars() {
+        let digit = c.is_alphanumeric().then(|| c.is_numeric());
+        if digit.is_some() && digit != previous {
+            abbreviation.push(c);
+        }
+        previous = digit;
+    }
+    abbreviation
+}
+
+thread_local! {
+    static TRUNCATED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Whether a name query on this thread stopped at its limit since the last call.
+// `Index::query` only hands back matches, so callers of `Db::query` learn about partial results
+// here, right after the query since it runs on the calling thread.
+pub fn take_truncated() -> bool {
+    TRUNCATED.with(|truncated| truncated.replace(false))
+}
+
+// Ids and texts of every text containing each run of `N` bytes.
+struct Grams<const N: usize>(FxHashMap<[u8; N], FxHashMap<ID, Arc<str>>>);
+
+impl<const N: usize> Default for Grams<N> {
+    fn default() -> Self {
+        Self(FxHashMap::default())
+    }
+}
+
+impl<const N: usize> Grams<N> {
+    fn grams(text: &str) -> impl Iterator<Item = [u8; N]> + '_ {
+        text.as_bytes()
+            .windows(N)
+            .map(|gram| gram.try_into().unwrap())
+    }
+
+    fn insert(&mut self, id: ID, text: &Arc<str>) {
+        for gram in Self::grams(text) {
+            self.0.entry(gram).or_default().insert(id, text.clone());
+        }
+    }
+
+    fn remove(&mut self, id: ID, text: &str) {
+        for gram in Self::grams(text) {
+            let Some(bucket) = self.0.get_mut(&gram) else {
+                continue;
+            };
+            bucket.remove(&id);
+            if bucket.is_empty() {
+                self.0.remove(&gram);
+            }
+        }
+    }
+
+    // The smallest bucket among the grams of `text`, every text containing `text` is in it.
+    // None if a gram has no texts at all.
+    fn query(&self, text: &str) -> Option<&FxHashMap<ID, Arc<str>>> {
+        Self::grams(text)
+            .map(|gram| self.0.get(&gram))
+            .min_by_key(|bucket| bucket.map_or(0, |bucket| bucket.len()))?
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (gram, bucket) in other.0 {
+            match self.0.entry(gram) {
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket);
+                }
+                Entry::Occupied(mut entry) => entry.get_mut().extend(bucket),
+            }
+        }
+    }
+}
+
+// Texts per `NgramSearch::build` chunk, each chunk is indexed on its own thread.
+const BUILD_CHUNK: usize = 16_384;
+
+// 1 and 2 grams over arbitrary text, resolves `TextQuery`s without scanning every item.
+// Optionally 3 grams too, which narrow longer queries much further but take more memory.
+pub struct NgramSearch {
+    n1gram_index: Grams<1>,
+    n2gram_index: Grams<2>,
+    n3gram_index: Option<Grams<3>>,
+    limit: usize,
+}
+
+impl NgramSearch {
+    pub fn new(limit: usize, trigrams: bool) -> Self {
+        Self {
+            n1gram_index: Grams::default(),
+            n2gram_index: Grams::default(),
+            n3gram_index: trigrams.then(Grams::default),
+            limit,
+        }
+    }
+
+    // Same as inserting every text into `new`, but chunks of `texts` are indexed in parallel
+    // and merged, loading millions of texts one by one is slow.
+    pub fn build(limit: usize, trigrams: bool, texts: &[(ID, Arc<str>)]) -> Self {
+        texts
+            .par_chunks(BUILD_CHUNK)
+            .map(|chunk| {
+                let mut ngrams = Self::new(limit, trigrams);
+                for (id, text) in chunk {
+                    ngrams.insert(*id, text.clone());
+                }
+                ngrams
+            })
+            .reduce(
+                || Self::new(limit, trigrams),
+                |mut ngrams, other| {
+                    ngrams.merge(other);
+                    ngrams
+                },
+            )
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.n1gram_index.merge(other.n1gram_index);
+        self.n2gram_index.merge(other.n2gram_index);
+        if let (Some(n3gram_index), Some(other)) = (&mut self.n3gram_index, other.n3gram_index) {
+            n3gram_index.merge(other);
+        }
+    }
+
+    pub fn insert(&mut self, id: ID, text: Arc<str>) {
+        if let Some(n3gram_index) = &mut self.n3gram_index {
+            n3gram_index.insert(id, &text);
+        }
+        self.n1gram_index.insert(id, &text);
+        self.n2gram_index.insert(id, &text);
+    }
+
+    pub fn remove(&mut self, id: ID, text: Arc<str>) {
+        if let Some(n3gram_index) = &mut self.n3gram_index {
+            n3gram_index.remove(id, &text);
+        }
+        self.n1gram_index.remove(id, &text);
+        self.n2gram_index.remove(id, &text);
+    }
+
+    // At most `limit` matches, and whether there were more.
+    pub fn query(&self, query: TextQuery) -> (Vec<ID>, bool) {
+        let text = query.text();
+        // Longest gram that fits in the text, its buckets are the most selective.
+        let (gram, smallest) = match (text.len(), &self.n3gram_index) {
+            (0, _) => return (Vec::new(), false),
+            (1, _) => (1, self.n1gram_index.query(text)),
+            (2, _) | (_, None) => (2, self.n2gram_index.query(text)),
+            (_, Some(n3gram_index)) => (3, n3gram_index.query(text)),
+        };
+        let Some(smallest) = smallest else {
+            return (Vec::new(), false);
+        };
+        let matches = |t: &str| match &query {
+            TextQuery::StartsWith(text) => t.starts_with(text.as_str()),
+            // Every item in the bucket contains the whole text.
+            TextQuery::Contains(text) if text.len() <= gram => true,
+            TextQuery::Contains(text) => t.contains(text.as_str()),
+            TextQuery::EndsWith(text) => t.ends_with(text.as_str()),
+        };
+        let mut ids: Vec<_> = smallest
+            .iter()
+            .filter(|(_, t)| matches(t))
+            .map(|(id, _)| *id)
+            .take(self.limit.saturating_add(1))
+            .collect();
+        let truncated = ids.len() > self.limit;
+        ids.truncate(self.limit);
+        (ids, truncated)
+    }
+}
+
+pub struct NameIndexLoader<T> {
+    abbreviations: KeyIndexLoader<String>,
+    // Indexed all at once by `NgramSearch::build` when loaded.
+    names: Vec<(ID, Arc<str>)>,
+    limit: usize,
+    trigrams: bool,
+    _item: PhantomData<fn(&T)>,
+}
+
+impl<T> NameIndexLoader<T> {
+    // Queries stop collecting matches past `limit` so `*a*` style queries stay cheap, see
+    // `take_truncated`. `trigrams` also keeps 3 grams, see `NgramSearch`.
+    pub fn new(limit: usize, trigrams: bool) -> Self {
+        Self {
+            abbreviations: KeyIndexLoader::new(),
+            names: Vec::new(),
+            limit,
+            trigrams,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T: Named + 'static> IndexLoader<T> for NameIndexLoader<T> {
+    fn add(&mut self, id: ID, item: &T) {
+        let abv = abbreviate(item.name());
+        self.abbreviations.add(id, &abv);
+        self.names.push((id, item.name().clone()));
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<T>> {
+        Box::new(NameIndex {
+            abbreviations: self.abbreviations.load(),
+            ngrams: NgramSearch::build(self.limit, self.trigrams, &self.names),
+            _item: PhantomData,
+        })
+    }
+}
+
+pub struct NameIndex<T> {
+    abbreviations: KeyIndex<String>,
+    ngrams: NgramSearch,
+    _item: PhantomData<fn(&T)>,
+}
+
+impl<T: Named> Index<T> for NameIndex<T> {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        if let Some(abv) = text.strip_prefix('/') {
+            let queryable = self
+                .abbreviations
+                .get(abv)
+                .unwrap_or(Queryable::IDsOwned(vec![]));
+            return Some(Query::new(Item::Single(queryable), inverse));
+        }
+        let query: TextQuery = text.parse().ok()?;
+        let (ids, truncated) = self.ngrams.query(query);
+        if truncated {
+            TRUNCATED.with(|cell| cell.set(true));
+        }
+        let item = Item::Single(Queryable::IDsOwned(ids));
+        Some(Query::new(item, inverse))
+    }
+
+    fn insert(&mut self, id: ID, item: &T) {
+        let abv = abbreviate(item.name());
+        self.abbreviations.insert(id, &abv);
+        self.ngrams.insert(id, item.name().clone());
+    }
+
+    fn remove(&mut self, id: ID, item: &T) {
+        let abv = abbreviate(item.name());
+        self.abbreviations.remove(id, &abv);
+        self.ngrams.remove(id, item.name().clone());
+    }
+
+    fn update(&mut self, id: ID, old: &T, new: &T) {
+        if old.name() == new.name() {
+            return;
+        }
+        self.remove(id, old);
+        self.insert(id, new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abbreviations() {
+        assert_eq!(abbreviate("hatsune_miku_(vocaloid)"), "hmv");
+        assert_eq!(abbreviate("re:zero"), "rz");
+        assert_eq!(abbreviate("2girls"), "2g");
+        assert_eq!(abbreviate("1girl"), "1g");
+        assert_eq!(abbreviate("girls_und_panzer"), "gup");
+        assert_eq!(abbreviate("mp5k2"), "m5k2");
+        assert_eq!(abbreviate("^_^"), "");
+        assert_eq!(abbreviate(""), "");
+        assert_eq!(abbreviate("__abc__"), "a");
+        assert_eq!(abbreviate("ポケモン_黒"), "ポ黒");
+    }
+
+    #[test]
+    fn query_reports_truncation() {
+        let mut ngrams = NgramSearch::new(2, true);
+        for (id, name) in ["cat", "cats", "catgirl", "dog"].into_iter().enumerate() {
+            ngrams.insert(id as ID, name.into());
+        }
+        let query = |text: &str| ngrams.query(text.parse().unwrap());
+        let (ids, truncated) = query("cat*");
+        assert_eq!((ids.len(), truncated), (2, true));
+        let (ids, truncated) = query("*og");
+        assert_eq!((ids, truncated), (vec![3], false));
+        let (ids, truncated) = query("*girl*");
+        assert_eq!((ids, truncated), (vec![2], false));
+    }
+
+    #[test]
+    fn build_matches_inserting() {
+        // Enough texts for several chunks, sharing grams across them.
+        let texts: Vec<(ID, Arc<str>)> = (0..BUILD_CHUNK as ID * 3)
+            .map(|id| (id, format!("tag_{id}_{}", id % 7).into()))
+            .collect();
+        let built = NgramSearch::build(usize::MAX, true, &texts);
+        let mut inserted = NgramSearch::new(usize::MAX, true);
+        for (id, text) in &texts {
+            inserted.insert(*id, text.clone());
+        }
+        let ids = |ngrams: &NgramSearch, text: &str| {
+            let (mut ids, _) = ngrams.query(text.parse().unwrap());
+            ids.sort_unstable();
+            ids
+        };
+        for text in ["t*", "*_3", "tag_4*", "*0_*", "*99*", "*_10000_*", "x*"] {
+            assert_eq!(ids(&built, text), ids(&inserted, text), "{text}");
+        }
+        assert_eq!(ids(&built, "*_12345_*"), [12345]);
+
+        let mut built = built;
+        built.remove(12345, texts[12345].1.clone());
+        assert_eq!(ids(&built, "*_12345_*"), [] as [ID; 0]);
+    }
+}
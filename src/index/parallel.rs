@@ -0,0 +1,95 @@
+use std::iter;
+
+use booru_db::{
+    index::{Index, IndexLoader},
+    ID,
+};
+use rayon::prelude::*;
+
+use super::PostIndexLoader;
+use crate::{post::FileExt, BooruPost, Db, DbLoader};
+
+pub type BoxedLoader<T> = Box<dyn IndexLoader<T> + Send>;
+
+// Runs every loader over the whole slice, one loader per thread.
+// Ids are assigned by position, matching the order `DbLoader::load` hands them out.
+pub fn fill_parallel<T: Sync>(items: &[T], loaders: &mut [BoxedLoader<T>]) {
+    loaders.par_iter_mut().for_each(|loader| {
+        for (id, item) in items.iter().enumerate() {
+            loader.add(id as ID, item);
+        }
+    });
+}
+
+// A loader that has already seen every item, so the serial `DbLoader` pass only assigns ids.
+pub struct Prefilled<T>(pub BoxedLoader<T>);
+
+impl<T> IndexLoader<T> for Prefilled<T> {
+    fn add(&mut self, _id: ID, _item: &T) {}
+
+    fn load(self: Box<Self>) -> Box<dyn Index<T>> {
+        self.0.load()
+    }
+}
+
+#[derive(Default)]
+pub struct ParallelDbLoader {
+    // `None` marks the default loader.
+    loaders: Vec<(Option<&'static str>, BoxedLoader<BooruPost>)>,
+    // Name of the index the posts themselves are moved into.
+    posts: Option<&'static str>,
+}
+
+impl ParallelDbLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_loader(
+        mut self,
+        name: &'static str,
+        loader: impl IndexLoader<BooruPost> + Send + 'static,
+    ) -> Self {
+        self.loaders.push((Some(name), Box::new(loader)));
+        self
+    }
+
+    pub fn with_default(mut self, loader: impl IndexLoader<BooruPost> + Send + 'static) -> Self {
+        self.loaders.push((None, Box::new(loader)));
+        self
+    }
+
+    pub fn with_posts(mut self, name: &'static str) -> Self {
+        self.posts = Some(name);
+        self
+    }
+
+    pub fn load(self, posts: Vec<BooruPost>) -> Db {
+        let (names, mut loaders): (Vec<_>, Vec<_>) = self.loaders.into_iter().unzip();
+        fill_parallel(&posts, &mut loaders);
+
+        let mut db_loader = DbLoader::new();
+        for (name, loader) in names.into_iter().zip(loaders) {
+            let loader = Prefilled(loader);
+            db_loader = match name {
+                Some(name) => db_loader.with_loader(name, loader),
+                None => db_loader.with_default(loader),
+            };
+        }
+        // Every loader is filled already, the serial pass only needs one item per post to hand
+        // out ids. A post without heap data stands in for each, the posts move into their index.
+        let count = posts.len();
+        let stand_in = posts.first().map(|post| BooruPost {
+            source: String::new(),
+            file_ext: FileExt::JPG,
+            pools: Vec::new(),
+            favs: Vec::new(),
+            tags: Vec::new(),
+            ..post.clone()
+        });
+        if let Some(name) = self.posts {
+            db_loader = db_loader.with_loader(name, PostIndexLoader::new(posts));
+        }
+        db_loader.load(iter::repeat(stand_in).take(count).flatten())
+    }
+}
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+
+use super::{id::PostIds, TryQuery};
+use crate::{error::ParseError, BooruPost};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ParentId(Option<u32>);
+impl FromStr for ParentId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(ParentId(None));
+        }
+        s.parse::<u32>()
+            .map(|i| Self(Some(i)))
+            .map_err(|_| ParseError::NotANumber)
+    }
+}
+
+pub struct ParentIdIndexLoader {
+    key_loader: KeyIndexLoader<ParentId>,
+    post_ids: PostIds,
+}
+
+impl ParentIdIndexLoader {
+    pub fn new(post_ids: PostIds) -> Self {
+        Self {
+            key_loader: KeyIndexLoader::new(),
+            post_ids,
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for ParentIdIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.key_loader.add(id, &ParentId(post.parent_id));
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(ParentIdIndex {
+            key_index: self.key_loader.load(),
+            post_ids: self.post_ids,
+        })
+    }
+}
+
+// Like danbooru `parent:123` matches post 123 itself as well as its children.
+pub struct ParentIdIndex {
+    key_index: KeyIndex<ParentId>,
+    post_ids: PostIds,
+}
+
+impl ParentIdIndex {
+    fn family(&self, parent_id: ParentId) -> Vec<Query<Queryable<'_>>> {
+        let mut items = Vec::new();
+        if let Some(queryable) = self.key_index.get(&parent_id) {
+            items.push(Query::new(Item::Single(queryable), false));
+        }
+        if let Some(id) = parent_id.0.and_then(|post_id| self.post_ids.get(post_id)) {
+            let queryable = Queryable::IDsOwned(vec![id]);
+            items.push(Query::new(Item::Single(queryable), false));
+        }
+        items
+    }
+}
+
+impl TryQuery for ParentIdIndex {
+    fn try_query<'s>(
+        &'s self,
+        text: &str,
+        inverse: bool,
+    ) -> Result<Query<Queryable<'s>>, ParseError> {
+        let parent_ids: Vec<ParentId> =
+            text.split(',').map(str::parse).collect::<Result<_, _>>()?;
+        let or_chain: Vec<_> = parent_ids
+            .into_iter()
+            .flat_map(|parent_id| self.family(parent_id))
+            .collect();
+        let item = match or_chain.is_empty() {
+            true => Item::Single(Queryable::IDsOwned(vec![])),
+            false => Item::OrChain(or_chain),
+        };
+        Ok(Query::new(item, inverse))
+    }
+}
+
+impl Index<BooruPost> for ParentIdIndex {
+    fn query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.reported_query(ident, text, inverse)
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.insert(id, &ParentId(post.parent_id));
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.remove(id, &ParentId(post.parent_id));
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        let old_key = ParentId(old.parent_id);
+        let new_key = ParentId(new.parent_id);
+        self.key_index.update(id, &old_key, &new_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        index::IdIndex,
+        post::tests::post,
+        tests::{db, search},
+        BooruPost,
+    };
+
+    fn child(id: i32, parent_id: u32) -> BooruPost {
+        BooruPost {
+            parent_id: Some(parent_id),
+            ..post(id)
+        }
+    }
+
+    #[test]
+    fn parent_matches_itself_and_every_child() {
+        let mut db = db(vec![
+            child(2, 9),
+            child(3, 9),
+            post(9),
+            child(4, 5),
+            post(5),
+        ]);
+        assert_eq!(search(&db, "parent_id:9"), [2, 3, 9]);
+        assert_eq!(search(&db, "parent_id:5,9"), [2, 3, 4, 5, 9]);
+        assert_eq!(search(&db, "-parent_id:9"), [4, 5]);
+
+        // A parent whose post id changes is found under its new one.
+        let id_index: &IdIndex = db.index().unwrap();
+        let id = id_index.post_id_to_id(9).unwrap();
+        db.update(id, &post(9), &post(8));
+        assert_eq!(search(&db, "parent_id:9"), [2, 3]);
+        assert_eq!(search(&db, "parent_id:8"), [8]);
+    }
+}
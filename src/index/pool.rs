@@ -0,0 +1,80 @@
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader, KeysIndex, KeysIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+
+use crate::BooruPost;
+
+pub struct PoolIndexLoader {
+    keys_loader: KeysIndexLoader<u32>,
+    any_loader: KeyIndexLoader<bool>,
+}
+
+impl Default for PoolIndexLoader {
+    fn default() -> Self {
+        Self {
+            keys_loader: KeysIndexLoader::new(),
+            any_loader: KeyIndexLoader::new(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for PoolIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.keys_loader.add(id, post.pools.iter());
+        self.any_loader.add(id, &!post.pools.is_empty());
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(PoolIndex {
+            keys_index: self.keys_loader.load(),
+            any_index: self.any_loader.load(),
+        })
+    }
+}
+
+pub struct PoolIndex {
+    pub keys_index: KeysIndex<u32>,
+    any_index: KeyIndex<bool>,
+}
+
+impl Index<BooruPost> for PoolIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        let queryable = match text {
+            "any" => self.any_index.get(&true),
+            "none" => self.any_index.get(&false),
+            _ => {
+                let pool_id: u32 = text.parse().ok()?;
+                self.keys_index.get(&pool_id)
+            }
+        }
+        // A pool no post is in yet, `-pool:` then matches every post.
+        .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.insert(id, post.pools.iter());
+        self.any_index.insert(id, &!post.pools.is_empty());
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.remove(id, post.pools.iter());
+        self.any_index.remove(id, &!post.pools.is_empty());
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.pools == new.pools {
+            return;
+        }
+        self.keys_index.update(id, &old.pools, &new.pools);
+        self.any_index
+            .update(id, &!old.pools.is_empty(), &!new.pools.is_empty());
+    }
+}
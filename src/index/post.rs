@@ -0,0 +1,76 @@
+use booru_db::{
+    index::{Index, IndexLoader},
+    Query, Queryable, ID,
+};
+use fxhash::FxHashMap;
+
+use crate::BooruPost;
+
+pub struct PostIndexLoader {
+    posts: FxHashMap<ID, BooruPost>,
+}
+
+impl PostIndexLoader {
+    // Takes the loaded posts over instead of copying each, ids are their positions.
+    pub fn new(posts: Vec<BooruPost>) -> Self {
+        let posts = posts
+            .into_iter()
+            .enumerate()
+            .map(|(id, post)| (id as ID, post))
+            .collect();
+        Self { posts }
+    }
+}
+
+impl IndexLoader<BooruPost> for PostIndexLoader {
+    fn add(&mut self, _id: ID, _post: &BooruPost) {}
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(PostIndex { posts: self.posts })
+    }
+}
+
+// Keeps a copy of every post so responses can include more than the post id.
+pub struct PostIndex {
+    posts: FxHashMap<ID, BooruPost>,
+}
+
+impl PostIndex {
+    pub fn get(&self, id: ID) -> Option<&BooruPost> {
+        self.posts.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.posts.len()
+    }
+
+    // Every post, in id order.
+    pub fn posts(&self) -> impl Iterator<Item = &BooruPost> {
+        let mut ids: Vec<_> = self.posts.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter().map(|id| &self.posts[&id])
+    }
+}
+
+impl Index<BooruPost> for PostIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        _text: &str,
+        _inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        None
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.posts.insert(id, post.clone());
+    }
+
+    fn remove(&mut self, id: ID, _post: &BooruPost) {
+        self.posts.remove(&id);
+    }
+
+    fn update(&mut self, id: ID, _old: &BooruPost, new: &BooruPost) {
+        self.posts.insert(id, new.clone());
+    }
+}
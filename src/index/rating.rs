@@ -0,0 +1,150 @@
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader, RangeIndex, RangeIndexLoader},
+    query::Item,
+    Query, Queryable, ID,
+};
+
+use super::{range_error, TryQuery};
+use crate::{error::ParseError, post::Rating, BooruPost};
+
+pub struct RatingIndexLoader {
+    key_loader: KeyIndexLoader<Rating>,
+    range_loader: RangeIndexLoader<Rating>,
+}
+
+impl Default for RatingIndexLoader {
+    fn default() -> Self {
+        Self {
+            key_loader: KeyIndexLoader::new(),
+            range_loader: RangeIndexLoader::new(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for RatingIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.key_loader.add(id, &post.rating);
+        self.range_loader.add(id, post.rating);
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(RatingIndex {
+            key_index: self.key_loader.load(),
+            range_index: self.range_loader.load(),
+        })
+    }
+}
+
+pub struct RatingIndex {
+    key_index: KeyIndex<Rating>,
+    pub range_index: RangeIndex<Rating>,
+}
+
+fn is_range(text: &str) -> bool {
+    text.starts_with(['<', '>']) || text.contains("..")
+}
+
+impl TryQuery for RatingIndex {
+    fn try_query<'s>(
+        &'s self,
+        text: &str,
+        inverse: bool,
+    ) -> Result<Query<Queryable<'s>>, ParseError> {
+        if is_range(text) {
+            let range_query = text.parse().map_err(|_| range_error::<Rating>(text))?;
+            let mut query = self.range_index.get(range_query);
+            query.inverse = inverse;
+            return Ok(query);
+        }
+        if text.contains(',') {
+            let ratings: Vec<Rating> = text.split(',').map(str::parse).collect::<Result<_, _>>()?;
+            let or_chain: Vec<_> = ratings
+                .iter()
+                .filter_map(|rating| self.key_index.get(rating))
+                .map(|queryable| Query::new(Item::Single(queryable), false))
+                .collect();
+            let item = match or_chain.is_empty() {
+                true => Item::Single(Queryable::IDsOwned(vec![])),
+                false => Item::OrChain(or_chain),
+            };
+            return Ok(Query::new(item, inverse));
+        }
+        let rating: Rating = text.parse()?;
+        // No posts with this rating yet, `-rating:` then matches every post.
+        let queryable = self
+            .key_index
+            .get(&rating)
+            .unwrap_or(Queryable::IDsOwned(vec![]));
+        Ok(Query::new(Item::Single(queryable), inverse))
+    }
+}
+
+impl Index<BooruPost> for RatingIndex {
+    fn query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.reported_query(ident, text, inverse)
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.insert(id, &post.rating);
+        self.range_index.insert(id, post.rating);
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.key_index.remove(id, &post.rating);
+        self.range_index.remove(id, post.rating);
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.rating == new.rating {
+            return;
+        }
+        self.key_index.update(id, &old.rating, &new.rating);
+        self.range_index.update(id, old.rating, new.rating);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        post::{tests::post, Rating},
+        tests::{db, search},
+        BooruPost,
+    };
+
+    #[test]
+    fn every_rating_alias() {
+        let rated = |id: i32, rating: Rating| BooruPost { rating, ..post(id) };
+        let db = db(vec![
+            rated(1, Rating::G),
+            rated(2, Rating::S),
+            rated(3, Rating::Q),
+            rated(4, Rating::E),
+        ]);
+        let aliases = [
+            (["g", "general", "safe"].as_slice(), 1),
+            (&["s", "sensitive"], 2),
+            (&["q", "questionable"], 3),
+            (&["e", "explicit"], 4),
+        ];
+        for (names, post_id) in aliases {
+            for name in names {
+                assert_eq!(search(&db, &format!("rating:{name}")), [post_id], "{name}");
+                let upper = name.to_uppercase();
+                assert_eq!(
+                    search(&db, &format!("rating:{upper}")),
+                    [post_id],
+                    "{upper}"
+                );
+            }
+        }
+        assert_eq!(search(&db, "rating:safe,explicit"), [1, 4]);
+        assert_eq!(search(&db, "-rating:general,sensitive"), [3, 4]);
+        assert_eq!(search(&db, "rating:>=questionable"), [3, 4]);
+        assert_eq!(search(&db, "rating:<s"), [1]);
+    }
+}
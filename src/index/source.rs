@@ -0,0 +1,240 @@
+use std::sync::Arc;
+
+use booru_db::{
+    index::{Index, IndexLoader, KeysIndex, KeysIndexLoader},
+    query::Item,
+    Query, Queryable, TextQuery, ID,
+};
+
+use super::name::NgramSearch;
+use crate::BooruPost;
+
+// Canonical service names and the hosts they are served from, subdomains of these match too.
+const SERVICES: &[(&str, &[&str])] = &[
+    ("artstation", &["artstation.com"]),
+    (
+        "deviantart",
+        &["deviantart.com", "deviantart.net", "fav.me"],
+    ),
+    ("fanbox", &["fanbox.cc"]),
+    ("fantia", &["fantia.jp"]),
+    ("nijie", &["nijie.info", "nijie.net"]),
+    ("pixiv", &["pixiv.net", "pximg.net"]),
+    ("skeb", &["skeb.jp"]),
+    ("tumblr", &["tumblr.com"]),
+    ("twitter", &["twitter.com", "x.com", "twimg.com", "t.co"]),
+];
+
+fn host(source: &str) -> Option<String> {
+    let rest = source.split_once("://").map_or(source, |(_, rest)| rest);
+    let host = rest.split(['/', '?', '#']).next()?;
+    let host = host.rsplit('@').next()?.split(':').next()?;
+    let host = host.to_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .map(str::to_string)
+        .unwrap_or(host);
+    (!host.is_empty()).then_some(host)
+}
+
+fn service(host: &str) -> Option<&'static str> {
+    SERVICES.iter().find_map(|(service, domains)| {
+        domains
+            .iter()
+            .any(|domain| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .then_some(*service)
+    })
+}
+
+// Artwork id of a pixiv page or image url, eg. `pixiv.net/artworks/12345`,
+// `pixiv.net/member_illust.php?illust_id=12345` or `i.pximg.net/.../12345_p0.jpg`.
+fn pixiv_artwork_id(source: &str) -> Option<u32> {
+    let host = host(source)?;
+    let rest = source.split_once("://").map_or(source, |(_, rest)| rest);
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let digits = |s: &str| -> Option<u32> {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        s[..end].parse().ok()
+    };
+    if host == "pixiv.net" || host.ends_with(".pixiv.net") {
+        if let Some((_, id)) = path.split_once("/artworks/") {
+            return digits(id);
+        }
+        let id = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("illust_id="))?;
+        return digits(id);
+    }
+    if host.ends_with("pximg.net") {
+        let file = path.rsplit('/').next()?;
+        let (id, _) = file.split_once('_')?;
+        return id.parse().ok();
+    }
+    None
+}
+
+// Each post is stored under its source host and, if known, the service that host belongs to.
+// Pixiv sources are also stored as `pixiv/<artwork id>` whichever url shape they use.
+fn source_keys(source: &str) -> Vec<String> {
+    let Some(host) = host(source) else {
+        return Vec::new();
+    };
+    let mut keys = match service(&host) {
+        Some(service) => vec![host, service.to_string()],
+        None => vec![host],
+    };
+    if let Some(id) = pixiv_artwork_id(source) {
+        keys.push(format!("pixiv/{id}"));
+    }
+    keys
+}
+
+pub struct SourceIndexLoader {
+    keys_loader: KeysIndexLoader<String>,
+    // Indexed all at once by `NgramSearch::build` when loaded.
+    sources: Vec<(ID, Arc<str>)>,
+}
+
+impl Default for SourceIndexLoader {
+    fn default() -> Self {
+        Self {
+            keys_loader: KeysIndexLoader::new(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for SourceIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.keys_loader.add(id, source_keys(&post.source).iter());
+        if !post.source.is_empty() {
+            self.sources.push((id, post.source.as_str().into()));
+        }
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        Box::new(SourceIndex {
+            keys_index: self.keys_loader.load(),
+            // Uncapped, a truncated `source:*foo*` would make `-source:*foo*` match posts that
+            // do contain `foo`.
+            ngrams: NgramSearch::build(usize::MAX, false, &self.sources),
+        })
+    }
+}
+
+pub struct SourceIndex {
+    keys_index: KeysIndex<String>,
+    // Full source urls for `*text*` style substring queries.
+    ngrams: NgramSearch,
+}
+
+impl Index<BooruPost> for SourceIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        if text.contains('*') {
+            let query: TextQuery = text.parse().ok()?;
+            let (ids, _) = self.ngrams.query(query);
+            let queryable = Queryable::IDsOwned(ids);
+            return Some(Query::new(Item::Single(queryable), inverse));
+        }
+        // Service names and `pixiv/<id>` are keys too, anything else is looked up as a host.
+        let text = text.to_lowercase();
+        let is_artwork = text
+            .strip_prefix("pixiv/")
+            .is_some_and(|id| id.parse::<u32>().is_ok());
+        let key = match is_artwork || SERVICES.iter().any(|(service, _)| *service == text) {
+            true => text,
+            false => host(&text)?,
+        };
+        // Hosts no post has match nothing, so `-source:host` matches every post.
+        let queryable = self
+            .keys_index
+            .get(key.as_str())
+            .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.insert(id, source_keys(&post.source).iter());
+        if !post.source.is_empty() {
+            self.ngrams.insert(id, post.source.as_str().into());
+        }
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.keys_index.remove(id, source_keys(&post.source).iter());
+        if !post.source.is_empty() {
+            self.ngrams.remove(id, post.source.as_str().into());
+        }
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.source == new.source {
+            return;
+        }
+        self.remove(id, old);
+        self.insert(id, new);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        post::tests::post,
+        tests::{db, search},
+    };
+
+    #[test]
+    fn pixiv_artwork_ids() {
+        let id = pixiv_artwork_id;
+        assert_eq!(id("https://www.pixiv.net/artworks/12345"), Some(12345));
+        assert_eq!(
+            id("https://www.pixiv.net/en/artworks/12345#big"),
+            Some(12345)
+        );
+        assert_eq!(
+            id("https://www.pixiv.net/member_illust.php?mode=medium&illust_id=12345"),
+            Some(12345)
+        );
+        assert_eq!(
+            id("https://i.pximg.net/img-original/img/2020/01/01/00/00/00/12345_p0.png"),
+            Some(12345)
+        );
+
+        assert_eq!(id("https://www.pixiv.net/artworks/"), None);
+        assert_eq!(id("https://www.pixiv.net/artworks/abc"), None);
+        assert_eq!(
+            id("https://www.pixiv.net/member_illust.php?mode=medium"),
+            None
+        );
+        assert_eq!(id("https://www.pixiv.net/users/678"), None);
+        assert_eq!(id("https://i.pximg.net/img-original/img/12345.png"), None);
+        assert_eq!(id("https://example.com/artworks/12345"), None);
+    }
+
+    #[test]
+    fn pixiv_urls_share_a_key() {
+        let sourced = |id: i32, source: &str| BooruPost {
+            source: source.into(),
+            ..post(id)
+        };
+        let db = db(vec![
+            sourced(1, "https://www.pixiv.net/artworks/12345"),
+            sourced(2, "https://www.pixiv.net/member_illust.php?illust_id=12345"),
+            sourced(3, "https://i.pximg.net/img-original/img/12345_p1.jpg"),
+            sourced(4, "https://www.pixiv.net/artworks/123456"),
+        ]);
+        assert_eq!(search(&db, "source:pixiv/12345"), [1, 2, 3]);
+        assert_eq!(search(&db, "source:pixiv/123456"), [4]);
+    }
+}
@@ -1,19 +1,33 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{cmp::Ordering, sync::Arc};
 
 use booru_db::{
     index::{
-        Index, IndexLoader, KeyIndex, KeyIndexLoader, KeysIndex, KeysIndexLoader, NgramIndex,
-        RangeIndex, RangeIndexLoader,
+        Index, IndexLoader, KeyIndex, KeyIndexLoader, KeysIndex, KeysIndexLoader, RangeIndex,
+        RangeIndexLoader,
     },
     query::Item,
-    Query, Queryable, RangeQuery, TextQuery, ID,
+    Query, Queryable, RangeQuery, ID,
 };
+use fxhash::FxHashMap;
+use tracing::warn;
 
-use crate::BooruPost;
+use super::{
+    name::{abbreviate, NameIndexLoader, Named},
+    parallel::{fill_parallel, BoxedLoader, Prefilled},
+    TryQuery,
+};
+use crate::{error::ParseError, post::TagCategory, BooruPost};
 
 pub struct Tag {
     name: Arc<str>,
     count: u32,
+    category: TagCategory,
+}
+
+impl Named for Tag {
+    fn name(&self) -> &Arc<str> {
+        &self.name
+    }
 }
 mod database {
     use super::Tag;
@@ -127,136 +141,135 @@ impl Index<Tag> for TagDbCountIndex {
     }
 }
 
-fn abbreviate(text: &str) -> String {
-    text.replace(|c| ['(', ')'].contains(&c), "")
-        .split('_')
-        .filter_map(|w| w.chars().next())
-        .collect()
+struct TagDbCategoryIndexLoader {
+    key_loader: KeyIndexLoader<TagCategory>,
 }
 
-#[derive(Default)]
-struct TagDbNameIndexLoader {
-    abbreviations: KeyIndexLoader<String>,
-    n1gram_index: NgramIndex<1>,
-    n2gram_index: NgramIndex<2>,
+impl Default for TagDbCategoryIndexLoader {
+    fn default() -> Self {
+        Self {
+            key_loader: KeyIndexLoader::new(),
+        }
+    }
 }
 
-impl IndexLoader<Tag> for TagDbNameIndexLoader {
+impl IndexLoader<Tag> for TagDbCategoryIndexLoader {
     fn add(&mut self, id: ID, tag: &Tag) {
-        let abv = abbreviate(&tag.name);
-        self.abbreviations.add(id, &abv);
-        self.n1gram_index.insert(id, tag.name.clone());
-        self.n2gram_index.insert(id, tag.name.clone());
+        self.key_loader.add(id, &tag.category);
     }
 
     fn load(self: Box<Self>) -> Box<dyn Index<Tag>> {
-        Box::new(TagDbNameIndex {
-            abbreviations: self.abbreviations.load(),
-            n1gram_index: self.n1gram_index,
-            n2gram_index: self.n2gram_index,
+        Box::new(TagDbCategoryIndex {
+            key_index: self.key_loader.load(),
         })
     }
 }
 
-#[derive(Default)]
-struct TagDbNameIndex {
-    abbreviations: KeyIndex<String>,
-    n1gram_index: NgramIndex<1>,
-    n2gram_index: NgramIndex<2>,
+pub struct TagDbCategoryIndex {
+    key_index: KeyIndex<TagCategory>,
 }
 
-impl Index<Tag> for TagDbNameIndex {
+impl Index<Tag> for TagDbCategoryIndex {
     fn query<'s>(
         &'s self,
         _ident: Option<&str>,
         text: &str,
         inverse: bool,
     ) -> Option<Query<Queryable<'s>>> {
-        if let Some(abv) = text.strip_prefix('/') {
-            return self
-                .abbreviations
-                .get(abv)
-                .map(|q| Query::new(Item::Single(q), inverse));
-        }
-        let query: TextQuery = text.parse().ok()?;
-        let text = query.text();
-        let Some(smallest) = (match text.len() {
-            0 => None,
-            1 => self.n1gram_index.query(text),
-            _ => self.n2gram_index.query(text),
-        }) else {
-            return Some(Query::new(
-                Item::Single(Queryable::IDsOwned(vec![])),
-                inverse,
-            ));
-        };
-        let mut ids = Vec::new();
-        match query {
-            TextQuery::StartsWith(text) => {
-                for (t, id) in smallest {
-                    if t.starts_with(&text) {
-                        ids.push(*id);
-                    }
-                }
-            }
-            TextQuery::Contains(text) => {
-                if text.len() <= 2 {
-                    ids.reserve(smallest.len());
-                    for (_, id) in smallest {
-                        ids.push(*id);
-                    }
-                } else {
-                    for (t, id) in smallest {
-                        if t.contains(&text) {
-                            ids.push(*id);
-                        }
-                    }
-                }
-            }
-            TextQuery::EndsWith(text) => {
-                for (t, id) in smallest {
-                    if t.ends_with(&text) {
-                        ids.push(*id);
-                    }
-                }
-            }
-        }
-        let queryable = Queryable::IDsOwned(ids);
-        let item = Item::Single(queryable);
-        Some(Query::new(item, inverse))
+        let category: TagCategory = text.parse().ok()?;
+        let queryable = self
+            .key_index
+            .get(&category)
+            .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
     }
 
     fn insert(&mut self, id: ID, tag: &Tag) {
-        let abv = abbreviate(&tag.name);
-        self.abbreviations.insert(id, &abv);
-        self.n1gram_index.insert(id, tag.name.clone());
-        self.n2gram_index.insert(id, tag.name.clone());
+        self.key_index.insert(id, &tag.category);
     }
 
     fn remove(&mut self, id: ID, tag: &Tag) {
-        let abv = abbreviate(&tag.name);
-        self.abbreviations.remove(id, &abv);
-        self.n1gram_index.remove(id, tag.name.clone());
-        self.n2gram_index.remove(id, tag.name.clone());
+        self.key_index.remove(id, &tag.category);
     }
 
     fn update(&mut self, id: ID, old: &Tag, new: &Tag) {
-        if old.name == new.name {
-            return;
+        self.key_index.update(id, &old.category, &new.category);
+    }
+}
+
+// Most used tags considered per lookup when suggesting a correction.
+const SUGGEST_CANDIDATES: usize = 1_000;
+
+// Number of single char insertions, deletions and substitutions turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
         }
-        self.remove(id, old);
-        self.insert(id, new);
     }
+    row[b.len()]
+}
+
+// Builds the secondary db of every tag with at least one post.
+fn build_tag_db(
+    keys_index: &KeysIndex<Arc<str>>,
+    categories: &FxHashMap<Arc<str>, TagCategory>,
+    name_query_limit: usize,
+) -> TagDb {
+    let tags: Vec<_> = keys_index
+        .items
+        .iter()
+        .map(|(name, queryable)| Tag {
+            // Create new Arc<str> instead of cloning. Makes initial tags close in memory.
+            name: name.to_string().into(),
+            count: queryable.matched() as u32,
+            category: categories.get(name).copied().unwrap_or_default(),
+        })
+        .filter(|tag| tag.count > 0)
+        .collect();
+    let mut loaders: [BoxedLoader<Tag>; 4] = [
+        // Tag names are short and wildcard searched a lot, worth the 3 grams.
+        Box::new(NameIndexLoader::<Tag>::new(name_query_limit, true)),
+        Box::<TagDbCountIndexLoader>::default(),
+        Box::<TagDbCategoryIndexLoader>::default(),
+        Box::<TagDbIdIndexLoader>::default(),
+    ];
+    fill_parallel(&tags, &mut loaders);
+    let [name, count, category, id] = loaders.map(Prefilled);
+    TagDbLoader::new()
+        .with_default(name)
+        .with_loader("count", count)
+        .with_loader("category", category)
+        .with_loader("id", id)
+        .load(tags.into_iter())
 }
 
 pub struct TagIndexLoader {
     keys_loader: KeysIndexLoader<Arc<str>>,
+    categories: FxHashMap<Arc<str>, TagCategory>,
+    wildcard_limit: usize,
+    name_query_limit: usize,
 }
 
-impl Default for TagIndexLoader {
-    fn default() -> Self {
+impl TagIndexLoader {
+    // Wildcards like `cat*` expand to at most `wildcard_limit` tags.
+    // Name queries on the tag db stop at `name_query_limit` matches.
+    pub fn new(
+        categories: FxHashMap<Arc<str>, TagCategory>,
+        wildcard_limit: usize,
+        name_query_limit: usize,
+    ) -> Self {
         Self {
             keys_loader: KeysIndexLoader::new(),
+            categories,
+            wildcard_limit,
+            name_query_limit,
         }
     }
 }
@@ -269,37 +282,136 @@ impl IndexLoader<BooruPost> for TagIndexLoader {
     fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
         let keys_index = self.keys_loader.load();
 
-        let tag_db = {
-            let tags = keys_index.items.iter().map(|(name, queryable)| Tag {
-                // Create new Arc<str> instead of cloning. Makes initial tags close in memory.
-                name: name.to_string().into(),
-                count: queryable.matched() as u32,
-            });
-            TagDbLoader::new()
-                .with_default(TagDbNameIndexLoader::default())
-                .with_loader("count", TagDbCountIndexLoader::default())
-                .with_loader("id", TagDbIdIndexLoader::default())
-                .load(tags)
+        let tag_db = build_tag_db(&keys_index, &self.categories, self.name_query_limit);
+        let index = TagIndex {
+            keys_index,
+            tag_db,
+            categories: self.categories,
+            wildcard_limit: self.wildcard_limit,
+            name_query_limit: self.name_query_limit,
         };
-        let index = TagIndex { keys_index, tag_db };
         Box::new(index)
     }
 }
 
+// Tags added to and removed from a post, by merging both lists in sorted order.
+// Danbooru keeps tag strings sorted so the sorts are usually no-ops.
+fn tag_diff<'t>(
+    old: &'t [Arc<str>],
+    new: &'t [Arc<str>],
+) -> (Vec<&'t Arc<str>>, Vec<&'t Arc<str>>) {
+    let sorted = |tags: &'t [Arc<str>]| {
+        let mut tags: Vec<_> = tags.iter().collect();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    };
+    let (old, new) = (sorted(old), sorted(new));
+    let (mut added, mut removed) = (Vec::new(), Vec::new());
+    let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+    loop {
+        match (old.peek(), new.peek()) {
+            (Some(o), Some(n)) => match o.cmp(n) {
+                Ordering::Less => removed.push(old.next().unwrap()),
+                Ordering::Greater => added.push(new.next().unwrap()),
+                Ordering::Equal => {
+                    old.next();
+                    new.next();
+                }
+            },
+            (Some(_), None) => removed.extend(old.by_ref()),
+            (None, Some(_)) => added.extend(new.by_ref()),
+            (None, None) => break,
+        }
+    }
+    (added, removed)
+}
+
 pub struct TagIndex {
     pub keys_index: KeysIndex<Arc<str>>,
     pub tag_db: TagDb,
+    // Tags missing from the tags table when loaded are treated as general.
+    categories: FxHashMap<Arc<str>, TagCategory>,
+    wildcard_limit: usize,
+    name_query_limit: usize,
 }
 
 impl TagIndex {
+    // Rebuilds the tag db from scratch, dropping whatever churn from syncing left behind.
+    // Returns the number of tags before and after.
+    pub fn compact(&mut self) -> (usize, usize) {
+        let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
+        let before = id_index.name_to_id.len();
+        self.tag_db = build_tag_db(&self.keys_index, &self.categories, self.name_query_limit);
+        let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
+        (before, id_index.name_to_id.len())
+    }
+
+    // Up to `limit` existing tags close to `name`, for when it matches nothing.
+    // Candidates share its abbreviation or its first or last few characters,
+    // the closest by edit distance are kept and ties go to the most used tag.
+    pub fn suggest(&self, name: &str, limit: usize) -> Vec<Arc<str>> {
+        let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
+        let count_index: &TagDbCountIndex = self.tag_db.index().unwrap();
+        let chars: Vec<char> = name.chars().collect();
+        let affix = chars.len().min(3);
+        let prefix: String = chars[..affix].iter().collect();
+        let suffix: String = chars[chars.len() - affix..].iter().collect();
+        let lookups = [
+            format!("/{}", abbreviate(name)),
+            format!("{prefix}*"),
+            format!("*{suffix}"),
+        ];
+
+        let max_distance = (chars.len() / 3).max(2);
+        let mut candidates: FxHashMap<ID, usize> = FxHashMap::default();
+        for lookup in lookups {
+            let query = Query::new(Item::Single(lookup), false);
+            let Ok(result) = self.tag_db.query(&query) else {
+                continue;
+            };
+            let sort = count_index.range_index.ids().iter().copied();
+            for id in result.get_sorted(sort, 0, SUGGEST_CANDIDATES, true) {
+                let Some(tag) = id_index.id_to_name.get(&id) else {
+                    continue;
+                };
+                let distance = edit_distance(name, tag);
+                if distance > 0 && distance <= max_distance {
+                    candidates.insert(id, distance);
+                }
+            }
+        }
+
+        let count = |id: &ID| count_index.range_index.id_values().get(id).copied();
+        let mut candidates: Vec<_> = candidates.into_iter().collect();
+        candidates.sort_unstable_by(|(a, a_distance), (b, b_distance)| {
+            a_distance.cmp(b_distance).then(count(b).cmp(&count(a)))
+        });
+        candidates
+            .into_iter()
+            .take(limit)
+            .filter_map(|(id, _)| id_index.id_to_name.get(&id).cloned())
+            .collect()
+    }
+
+    pub fn category(&self, name: &str) -> TagCategory {
+        self.categories.get(name).copied().unwrap_or_default()
+    }
+
     fn add_tag(&mut self, name: Arc<str>) {
         let count = self.keys_index.items.get(&name).unwrap().matched() as u32;
-        let tag = Tag { name, count };
+        let category = self.category(&name);
+        let tag = Tag {
+            name,
+            count,
+            category,
+        };
         let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
         if let Some(&id) = id_index.name_to_id.get(&tag.name) {
             let old = Tag {
                 name: tag.name.clone(),
                 count: tag.count - 1,
+                category,
             };
             self.tag_db.update(id, &old, &tag);
         } else {
@@ -315,7 +427,12 @@ impl TagIndex {
             .get(&name)
             .map(|q| q.matched() as u32)
             .unwrap_or(0);
-        let tag = Tag { name, count };
+        let category = self.category(&name);
+        let tag = Tag {
+            name,
+            count,
+            category,
+        };
         let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
         if let Some(&id) = id_index.name_to_id.get(&tag.name) {
             if tag.count == 0 {
@@ -324,6 +441,7 @@ impl TagIndex {
                 let old = Tag {
                     name: tag.name.clone(),
                     count: tag.count + 1,
+                    category,
                 };
                 self.tag_db.update(id, &old, &tag);
             }
@@ -331,20 +449,35 @@ impl TagIndex {
     }
 }
 
-impl Index<BooruPost> for TagIndex {
-    fn query<'s>(
+impl TryQuery for TagIndex {
+    fn try_query<'s>(
         &'s self,
-        _ident: Option<&str>,
         text: &str,
         inverse: bool,
-    ) -> Option<Query<Queryable<'s>>> {
+    ) -> Result<Query<Queryable<'s>>, ParseError> {
+        let tag_query = |text: &str| {
+            let query = Query::new(Item::Single(text.to_string()), false);
+            self.tag_db
+                .query(&query)
+                .map_err(|_| ParseError::BadPattern)
+        };
         if text.starts_with('*') || text.ends_with('*') {
             let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
-            let result = self
-                .tag_db
-                .query(&Query::new(Item::Single(text.into()), false))
-                .ok()?;
-            let ids = result.get(0, result.matched(), false);
+            let result = tag_query(text)?;
+            let matched = result.matched();
+            let limit = self.wildcard_limit;
+            // Dropping tags from `-*girl*` would let posts with them through, only a positive
+            // wildcard can be narrowed.
+            if matched > limit && inverse {
+                return Err(ParseError::TooBroad);
+            }
+            // Keep the most used tags if there are too many to OR together.
+            if matched > limit {
+                warn!(query = text, matched, "wildcard truncated to {limit} tags");
+            }
+            let count_index: &TagDbCountIndex = self.tag_db.index().unwrap();
+            let sort = count_index.range_index.ids().iter().copied();
+            let ids = result.get_sorted(sort, 0, limit, true);
             let tags: Vec<_> = ids
                 .into_iter()
                 .map(|id| {
@@ -353,23 +486,41 @@ impl Index<BooruPost> for TagIndex {
                     Query::new(Item::Single(queryable), false)
                 })
                 .collect();
-            let item = Item::OrChain(tags);
-            return Some(Query::new(item, inverse));
+            // `inverse` wraps the whole chain, `-*girl*` is posts with none of the matching tags.
+            let item = match tags.is_empty() {
+                true => Item::Single(Queryable::IDsOwned(vec![])),
+                false => Item::OrChain(tags),
+            };
+            return Ok(Query::new(item, inverse));
         }
         let queryable = if text.starts_with('/') {
-            let query = Query::new(Item::Single(text.to_string()), false);
-            let result = self.tag_db.query(&query).ok()?;
+            let result = tag_query(text)?;
             let count_index: &TagDbCountIndex = self.tag_db.index().unwrap();
             let sort = count_index.range_index.ids().iter().copied();
-            let id = *result.get_sorted(sort, 0, 1, true).first()?;
             let id_index: &TagDbIdIndex = self.tag_db.index().unwrap();
-            let name = id_index.id_to_name.get(&id)?;
-            self.keys_index.get(name)
+            result
+                .get_sorted(sort, 0, 1, true)
+                .first()
+                .and_then(|id| id_index.id_to_name.get(id))
+                .and_then(|name| self.keys_index.get(name))
         } else {
             self.keys_index.get(text)
-        }?;
+        }
+        // Tags no post has match nothing, so `-tag` matches every post.
+        .unwrap_or(Queryable::IDsOwned(vec![]));
         let item = Item::Single(queryable);
-        Some(Query::new(item, inverse))
+        Ok(Query::new(item, inverse))
+    }
+}
+
+impl Index<BooruPost> for TagIndex {
+    fn query<'s>(
+        &'s self,
+        ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        self.reported_query(ident, text, inverse)
     }
 
     fn insert(&mut self, id: ID, post: &BooruPost) {
@@ -393,17 +544,107 @@ impl Index<BooruPost> for TagIndex {
             return;
         }
         self.keys_index.update(id, &old.tags, &new.tags);
-        let old_tags: HashSet<&Arc<str>> = old.tags.iter().collect();
-        let new_tags: HashSet<&Arc<str>> = new.tags.iter().collect();
-        let added = new_tags.difference(&old_tags);
-        let removed = old_tags.difference(&new_tags);
-        for &tag in added {
-            let name = tag.clone();
-            self.add_tag(name);
+        let (added, removed) = tag_diff(&old.tags, &new.tags);
+        for tag in added {
+            self.add_tag(tag.clone());
         }
-        for &tag in removed {
-            let name = tag.clone();
-            self.remove_tag(name);
+        for tag in removed {
+            self.remove_tag(tag.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, sync::Arc, time::Instant};
+
+    use fxhash::FxHashSet;
+
+    use super::tag_diff;
+    use crate::{
+        config::Config, index::take_query_error, load_db, post::tests::post,
+        routes::query::parse_query, tests::search, BooruPost,
+    };
+
+    fn tagged(id: i32, tags: &[&str]) -> BooruPost {
+        BooruPost {
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+            ..post(id)
+        }
+    }
+
+    #[test]
+    fn negated_wildcards_are_never_truncated() {
+        let posts = || {
+            vec![
+                tagged(1, &["girl", "red"]),
+                tagged(2, &["girl", "blue"]),
+                tagged(3, &["catgirl"]),
+                tagged(4, &["girls"]),
+                tagged(5, &["dog"]),
+                tagged(6, &["catgirl"]),
+            ]
+        };
+        let config = Config {
+            tag_wildcard_limit: 2,
+            ..Config::default()
+        };
+        let db = load_db(posts(), Default::default(), Default::default(), &config);
+        // The most used matching tags are kept.
+        assert_eq!(search(&db, "*girl*"), [1, 2, 3, 6]);
+        take_query_error();
+        assert!(db.query(&parse_query("-*girl*").unwrap()).is_err());
+        assert_eq!(
+            take_query_error().as_deref(),
+            Some("-*girl*: matches too many tags to negate")
+        );
+
+        let db = load_db(
+            posts(),
+            Default::default(),
+            Default::default(),
+            &Config::default(),
+        );
+        assert_eq!(search(&db, "-*girl*"), [5]);
+        assert!(search(&db, "red -*girl*").is_empty());
+    }
+
+    // Run with `cargo test -- --ignored --nocapture` to see how the merge compares with the
+    // straightforward set difference on a typical edit of a heavily tagged post.
+    #[test]
+    #[ignore]
+    fn tag_diff_timing() {
+        let old: Vec<Arc<str>> = (0..150).map(|i| format!("tag_{i:03}").into()).collect();
+        let mut new: Vec<Arc<str>> = old[10..].to_vec();
+        new.extend((150..160).map(|i| format!("tag_{i:03}").into()));
+        new.sort_unstable();
+
+        let set_diff = |old: &[Arc<str>], new: &[Arc<str>]| {
+            let old_set: FxHashSet<_> = old.iter().collect();
+            let new_set: FxHashSet<_> = new.iter().collect();
+            let added: Vec<_> = new_set.difference(&old_set).copied().collect();
+            let removed: Vec<_> = old_set.difference(&new_set).copied().collect();
+            (added, removed)
+        };
+        let (mut added, mut removed) = set_diff(&old, &new);
+        added.sort_unstable();
+        removed.sort_unstable();
+        assert_eq!(tag_diff(&old, &new), (added, removed));
+
+        let runs = 10_000;
+        let start_time = Instant::now();
+        for _ in 0..runs {
+            black_box(tag_diff(black_box(&old), black_box(&new)));
+        }
+        let merge = start_time.elapsed();
+        let start_time = Instant::now();
+        for _ in 0..runs {
+            black_box(set_diff(black_box(&old), black_box(&new)));
         }
+        let set = start_time.elapsed();
+        println!(
+            "{runs} diffs of {} tags: merge {merge:?}, set {set:?}",
+            old.len()
+        );
     }
 }
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+
+use booru_db::{
+    index::{Index, IndexLoader, KeyIndex, KeyIndexLoader, RangeIndex, RangeIndexLoader},
+    query::Item,
+    Query, Queryable, RangeQuery, ID,
+};
+use fxhash::FxHashMap;
+
+use super::name::{NameIndexLoader, Named};
+use crate::BooruPost;
+
+pub struct User {
+    user_id: u32,
+    name: Arc<str>,
+    upload_count: u32,
+}
+
+impl Named for User {
+    fn name(&self) -> &Arc<str> {
+        &self.name
+    }
+}
+
+mod database {
+    use super::User;
+    use booru_db::db;
+    db!(User);
+}
+use database::{Db as UserDb, DbLoader as UserDbLoader};
+
+#[derive(Default)]
+struct UserDbIdIndexLoader {
+    user_id_to_id: FxHashMap<u32, ID>,
+    id_to_user_id: FxHashMap<ID, u32>,
+}
+
+impl IndexLoader<User> for UserDbIdIndexLoader {
+    fn add(&mut self, id: ID, user: &User) {
+        self.user_id_to_id.insert(user.user_id, id);
+        self.id_to_user_id.insert(id, user.user_id);
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<User>> {
+        Box::new(UserDbIdIndex {
+            user_id_to_id: self.user_id_to_id,
+            id_to_user_id: self.id_to_user_id,
+        })
+    }
+}
+
+pub struct UserDbIdIndex {
+    pub user_id_to_id: FxHashMap<u32, ID>,
+    pub id_to_user_id: FxHashMap<ID, u32>,
+}
+
+impl Index<User> for UserDbIdIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        _text: &str,
+        _inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        None
+    }
+
+    fn insert(&mut self, id: ID, user: &User) {
+        self.user_id_to_id.insert(user.user_id, id);
+        self.id_to_user_id.insert(id, user.user_id);
+    }
+
+    fn remove(&mut self, id: ID, user: &User) {
+        self.id_to_user_id.remove(&id);
+        self.user_id_to_id.remove(&user.user_id);
+    }
+
+    fn update(&mut self, id: ID, old: &User, new: &User) {
+        if old.user_id == new.user_id {
+            return;
+        }
+        self.remove(id, old);
+        self.insert(id, new);
+    }
+}
+
+#[derive(Default)]
+struct UserDbCountIndexLoader {
+    range_loader: RangeIndexLoader<u32>,
+}
+
+impl IndexLoader<User> for UserDbCountIndexLoader {
+    fn add(&mut self, id: ID, user: &User) {
+        self.range_loader.add(id, user.upload_count);
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<User>> {
+        Box::new(UserDbCountIndex {
+            range_index: self.range_loader.load(),
+        })
+    }
+}
+
+pub struct UserDbCountIndex {
+    pub range_index: RangeIndex<u32>,
+}
+
+impl Index<User> for UserDbCountIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        let query: RangeQuery<u32> = text.parse().ok()?;
+        let mut query = self.range_index.get(query);
+        query.inverse = inverse;
+        Some(query)
+    }
+
+    fn insert(&mut self, id: ID, user: &User) {
+        self.range_index.insert(id, user.upload_count);
+    }
+
+    fn remove(&mut self, id: ID, user: &User) {
+        self.range_index.remove(id, user.upload_count);
+    }
+
+    fn update(&mut self, id: ID, old: &User, new: &User) {
+        if old.upload_count == new.upload_count {
+            return;
+        }
+        self.remove(id, old);
+        self.insert(id, new);
+    }
+}
+
+pub struct UserIndexLoader {
+    names: FxHashMap<u32, Arc<str>>,
+    name_query_limit: usize,
+    uploads_loader: KeyIndexLoader<u32>,
+    upload_counts: FxHashMap<u32, u32>,
+}
+
+impl UserIndexLoader {
+    // Name queries on the user db stop at `name_query_limit` matches.
+    pub fn new(names: FxHashMap<u32, Arc<str>>, name_query_limit: usize) -> Self {
+        Self {
+            names,
+            name_query_limit,
+            uploads_loader: KeyIndexLoader::new(),
+            upload_counts: FxHashMap::default(),
+        }
+    }
+}
+
+impl IndexLoader<BooruPost> for UserIndexLoader {
+    fn add(&mut self, id: ID, post: &BooruPost) {
+        self.uploads_loader.add(id, &post.uploader_id);
+        *self.upload_counts.entry(post.uploader_id).or_default() += 1;
+    }
+
+    fn load(self: Box<Self>) -> Box<dyn Index<BooruPost>> {
+        let uploads = self.uploads_loader.load();
+
+        // Only users with at least one upload are searchable, same as tags with posts.
+        let user_db = {
+            let users = self.upload_counts.iter().filter_map(|(&user_id, &count)| {
+                let name = self.names.get(&user_id)?;
+                Some(User {
+                    user_id,
+                    name: name.clone(),
+                    upload_count: count,
+                })
+            });
+            UserDbLoader::new()
+                .with_default(NameIndexLoader::new(self.name_query_limit, false))
+                .with_loader("count", UserDbCountIndexLoader::default())
+                .with_loader("id", UserDbIdIndexLoader::default())
+                .load(users)
+        };
+        let user_ids = self
+            .names
+            .iter()
+            .map(|(&user_id, name)| (name.clone(), user_id))
+            .collect();
+        let index = UserIndex {
+            uploads,
+            upload_counts: self.upload_counts,
+            names: self.names,
+            user_ids,
+            user_db,
+        };
+        Box::new(index)
+    }
+}
+
+pub struct UserIndex {
+    uploads: KeyIndex<u32>,
+    pub upload_counts: FxHashMap<u32, u32>,
+    pub names: FxHashMap<u32, Arc<str>>,
+    user_ids: FxHashMap<Arc<str>, u32>,
+    pub user_db: UserDb,
+}
+
+impl UserIndex {
+    fn add_upload(&mut self, user_id: u32) {
+        let count = self.upload_counts.entry(user_id).or_default();
+        *count += 1;
+        let count = *count;
+        let Some(name) = self.names.get(&user_id) else {
+            return;
+        };
+        let user = User {
+            user_id,
+            name: name.clone(),
+            upload_count: count,
+        };
+        let id_index: &UserDbIdIndex = self.user_db.index().unwrap();
+        if let Some(&id) = id_index.user_id_to_id.get(&user_id) {
+            let old = User {
+                user_id,
+                name: user.name.clone(),
+                upload_count: count - 1,
+            };
+            self.user_db.update(id, &old, &user);
+        } else {
+            let id = self.user_db.next_id();
+            self.user_db.insert(id, &user);
+        }
+    }
+
+    fn remove_upload(&mut self, user_id: u32) {
+        let Some(count) = self.upload_counts.get_mut(&user_id) else {
+            return;
+        };
+        *count -= 1;
+        let count = *count;
+        if count == 0 {
+            self.upload_counts.remove(&user_id);
+        }
+        let Some(name) = self.names.get(&user_id) else {
+            return;
+        };
+        let user = User {
+            user_id,
+            name: name.clone(),
+            upload_count: count,
+        };
+        let id_index: &UserDbIdIndex = self.user_db.index().unwrap();
+        if let Some(&id) = id_index.user_id_to_id.get(&user_id) {
+            if count == 0 {
+                self.user_db.remove(id, &user);
+            } else {
+                let old = User {
+                    user_id,
+                    name: user.name.clone(),
+                    upload_count: count + 1,
+                };
+                self.user_db.update(id, &old, &user);
+            }
+        }
+    }
+}
+
+impl Index<BooruPost> for UserIndex {
+    fn query<'s>(
+        &'s self,
+        _ident: Option<&str>,
+        text: &str,
+        inverse: bool,
+    ) -> Option<Query<Queryable<'s>>> {
+        // Unknown users and users without uploads match no posts, so `-user:` matches every post.
+        let queryable = self
+            .user_ids
+            .get(text)
+            .and_then(|user_id| self.uploads.get(user_id))
+            .unwrap_or(Queryable::IDsOwned(vec![]));
+        Some(Query::new(Item::Single(queryable), inverse))
+    }
+
+    fn insert(&mut self, id: ID, post: &BooruPost) {
+        self.uploads.insert(id, &post.uploader_id);
+        self.add_upload(post.uploader_id);
+    }
+
+    fn remove(&mut self, id: ID, post: &BooruPost) {
+        self.uploads.remove(id, &post.uploader_id);
+        self.remove_upload(post.uploader_id);
+    }
+
+    fn update(&mut self, id: ID, old: &BooruPost, new: &BooruPost) {
+        if old.uploader_id == new.uploader_id {
+            return;
+        }
+        self.uploads.update(id, &old.uploader_id, &new.uploader_id);
+        self.remove_upload(old.uploader_id);
+        self.add_upload(new.uploader_id);
+    }
+}
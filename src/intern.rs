@@ -0,0 +1,36 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use fxhash::FxHashSet;
+
+// Hands out one shared `Arc<str>` per distinct string, tag names repeat across millions of posts.
+#[derive(Default)]
+pub struct Interner {
+    strings: Mutex<FxHashSet<Arc<str>>>,
+}
+
+impl Interner {
+    pub fn intern(&self, text: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+        if let Some(interned) = strings.get(text) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = text.into();
+        strings.insert(interned.clone());
+        interned
+    }
+
+    pub fn interned(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    // Forgets every string, those still in use stay shared.
+    pub fn clear(&self) {
+        *self.strings.lock().unwrap() = FxHashSet::default();
+    }
+}
+
+// Used by `From<RawBooruPost>` and `read_snapshot`.
+pub fn tag_names() -> &'static Interner {
+    static TAG_NAMES: OnceLock<Interner> = OnceLock::new();
+    TAG_NAMES.get_or_init(Interner::default)
+}
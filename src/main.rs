@@ -1,59 +1,190 @@
 use std::{
     net::SocketAddr,
-    sync::{mpsc::sync_channel, Arc},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+        mpsc::sync_channel,
+        Arc,
+    },
     time::Instant,
 };
 
-use axum::{routing::get, Router};
+use axum::{
+    extract::FromRef,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{get, post},
+    Router,
+};
 use booru_db::db;
 use futures::StreamExt;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch, RwLock};
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
 
+mod cache;
+use cache::QueryCache;
+mod config;
+use config::{arg_value, Config};
+mod error;
 mod index;
 use index::*;
+mod intern;
 mod post;
-use post::{BooruPost, RawBooruPost};
+use post::{BooruPost, RawBooruPost, TagCategory};
 mod routes;
-use routes::{posts::get_posts, tags::get_tags};
+use routes::{
+    admin::{post_compact, post_reload, post_verify},
+    health::{get_health, require_ready},
+    live::get_live,
+    log::access_log,
+    posts::{get_posts, get_random_posts, get_similar_posts, post_posts},
+    query::ScoreFloor,
+    stats::{get_histogram, get_stats},
+    tags::{get_tag_detail, get_tags, get_tags_autocomplete},
+    users::get_users,
+};
+mod snapshot;
+use snapshot::{read_snapshot, write_snapshot, SnapshotWriter};
 mod sync;
-use sync::{create_listener, handle_listener};
+use sync::{create_listener, handle_listener, LiveEvent, SyncLock};
 
 db!(BooruPost);
 
-// Create a trigger on postgres to notify us of changes.
-const SYNC: bool = true;
+#[derive(Clone)]
+pub struct Pools {
+    pub posts: sqlx::PgPool,
+    // Users and tags, the posts database unless `--meta-db` is passed.
+    pub meta: sqlx::PgPool,
+}
 
-#[tokio::main]
-async fn main() {
-    let (tx, rx) = sync_channel::<BooruPost>(1024);
-    let pg_listener = tokio::spawn(async move {
-        let uri = std::env::args().nth(1).unwrap();
-        let pool = sqlx::PgPool::connect(&uri).await.unwrap();
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Arc<RwLock<Db>>,
+    pub query_cache: Arc<QueryCache>,
+    // Milliseconds between the last synced row being updated upstream and it being applied.
+    pub sync_lag: Arc<AtomicI64>,
+    pub pools: Pools,
+    // `/admin` routes are refused unless this is set, from `BOORU_DB_ADMIN_TOKEN`.
+    pub admin_token: Option<Arc<str>>,
+    pub progress: Arc<LoadProgress>,
+    pub score_floor: ScoreFloor,
+    pub config: Config,
+    // Changes applied by sync, forwarded to `/live` subscribers.
+    pub live: broadcast::Sender<LiveEvent>,
+    pub sync_lock: SyncLock,
+}
 
-        let listener = if SYNC {
-            Some(create_listener(&uri, &pool).await)
-        } else {
-            None
-        };
+// The server starts before the posts are loaded, query routes answer 503 until `ready`.
+#[derive(Default)]
+pub struct LoadProgress {
+    pub loaded: AtomicUsize,
+    pub ready: AtomicBool,
+}
 
-        let mut posts = sqlx::query_as::<_, RawBooruPost>("SELECT * FROM posts").fetch(&pool);
-        let mut count = 0;
-        while let Some(Ok(post)) = posts.next().await {
-            tx.send(post.into()).unwrap();
-            count += 1;
-            if count % 50_000 == 0 {
-                println!("{count}");
-            }
-        }
+impl FromRef<AppState> for Arc<RwLock<Db>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
 
-        listener
-    });
+impl FromRef<AppState> for Arc<QueryCache> {
+    fn from_ref(state: &AppState) -> Self {
+        state.query_cache.clone()
+    }
+}
 
-    let posts = rx.iter();
-    let start_time = Instant::now();
-    let db = DbLoader::new()
-        .with_loader("id", IdIndexLoader::default())
-        .with_loader("parent_id", ParentIdIndexLoader::default())
+impl FromRef<AppState> for Arc<AtomicI64> {
+    fn from_ref(state: &AppState) -> Self {
+        state.sync_lag.clone()
+    }
+}
+
+impl FromRef<AppState> for Pools {
+    fn from_ref(state: &AppState) -> Self {
+        state.pools.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<LoadProgress> {
+    fn from_ref(state: &AppState) -> Self {
+        state.progress.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<str>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.admin_token.clone()
+    }
+}
+
+impl FromRef<AppState> for ScoreFloor {
+    fn from_ref(state: &AppState) -> Self {
+        state.score_floor
+    }
+}
+
+impl FromRef<AppState> for Config {
+    fn from_ref(state: &AppState) -> Self {
+        state.config
+    }
+}
+
+impl FromRef<AppState> for broadcast::Sender<LiveEvent> {
+    fn from_ref(state: &AppState) -> Self {
+        state.live.clone()
+    }
+}
+
+impl FromRef<AppState> for SyncLock {
+    fn from_ref(state: &AppState) -> Self {
+        state.sync_lock.clone()
+    }
+}
+
+// Results matching more posts than this are not cached.
+const QUERY_CACHE_MAX_IDS: usize = 250_000;
+
+// Events buffered per `/live` subscriber before it is dropped as lagging.
+const LIVE_CAPACITY: usize = 4096;
+
+// Used when neither `--bind` nor `BOORU_DB_BIND` are set.
+const DEFAULT_BIND: &str = "127.0.0.1:3000";
+
+pub async fn fetch_users(pool: &sqlx::PgPool) -> sqlx::Result<fxhash::FxHashMap<u32, Arc<str>>> {
+    let users = sqlx::query_as::<_, (i32, String)>("SELECT id, name FROM users")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(id, name)| (id as u32, name.into()))
+        .collect();
+    Ok(users)
+}
+
+pub async fn fetch_tag_categories(
+    pool: &sqlx::PgPool,
+) -> sqlx::Result<fxhash::FxHashMap<Arc<str>, TagCategory>> {
+    let tag_categories = sqlx::query_as::<_, (String, i32)>("SELECT name, category FROM tags")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .filter_map(|(name, category)| Some((name.into(), TagCategory::from_id(category)?)))
+        .collect();
+    Ok(tag_categories)
+}
+
+// Builds every index over `posts`, used on startup and by `/admin/reload`.
+pub fn load_db(
+    posts: Vec<BooruPost>,
+    users: fxhash::FxHashMap<u32, Arc<str>>,
+    tag_categories: fxhash::FxHashMap<Arc<str>, TagCategory>,
+    config: &Config,
+) -> Db {
+    let post_ids = PostIds::default();
+    ParallelDbLoader::new()
+        .with_loader("id", IdIndexLoader::new(post_ids.clone()))
+        .with_loader("parent_id", ParentIdIndexLoader::new(post_ids.clone()))
+        .with_loader("has", HasIndexLoader::new(post_ids))
         .with_loader("pixiv_id", PixivIdIndexLoader::default())
         .with_loader("approver", ApproverIdIndexLoader::default())
         .with_loader("status", StatusIndexLoader::default())
@@ -63,38 +194,314 @@ async fn main() {
         .with_loader("score", ScoreIndexLoader::default())
         .with_loader("upvotes", UpScoreIndexLoader::default())
         .with_loader("downvotes", DownScoreIndexLoader::default())
+        .with_loader("upratio", UpRatioIndexLoader::default())
+        .with_loader("comment_at", CommentAtIndexLoader::default())
+        .with_loader("note_at", NoteAtIndexLoader::default())
+        .with_loader("edited", EditDelayIndexLoader::default())
+        .with_loader("pop", PopularityIndexLoader::default())
         .with_loader("width", WidthIndexLoader::default())
         .with_loader("height", HeightIndexLoader::default())
         .with_loader("ratio", AspectRatioIndexLoader::default())
+        .with_loader("orientation", OrientationIndexLoader::default())
         .with_loader("mpixel", MPixelsIndexLoader::default())
         .with_loader("file_ext", FileExtIndexLoader::default())
-        .with_loader("file_size", FileSizeIndexLoader::default())
+        .with_loader("is", IsIndexLoader::default())
+        .with_loader("filesize", FileSizeIndexLoader::default())
         .with_loader("rating", RatingIndexLoader::default())
-        .with_default(TagIndexLoader::default())
+        .with_loader("source", SourceIndexLoader::default())
+        .with_loader("pool", PoolIndexLoader::default())
+        .with_loader("fav", FavIndexLoader::new(&users))
+        .with_loader("user", UserIndexLoader::new(users, config.name_query_limit))
+        .with_default(TagIndexLoader::new(
+            tag_categories,
+            config.tag_wildcard_limit,
+            config.name_query_limit,
+        ))
         .with_loader("tagcount", TagCountIndexLoader::default())
+        .with_loader("dbtagcount", DbTagCountIndexLoader::default())
         .with_loader("gentags", TagCountGeneralIndexLoader::default())
         .with_loader("arttags", TagCountArtistIndexLoader::default())
         .with_loader("chartags", TagCountCharacterIndexLoader::default())
         .with_loader("copytags", TagCountCopyrightIndexLoader::default())
         .with_loader("metatags", TagCountMetaIndexLoader::default())
-        .load(posts);
+        .with_posts("post")
+        .load(posts)
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = Config::from_args();
+    let snapshot = arg_value("--snapshot").map(PathBuf::from);
+    let bind = arg_value("--bind")
+        .or_else(|| std::env::var("BOORU_DB_BIND").ok())
+        .unwrap_or_else(|| DEFAULT_BIND.to_string());
+    let addr = SocketAddr::from_str(&bind)
+        .unwrap_or_else(|error| panic!("invalid bind address {bind:?}: {error}"));
+    // Sync creates a trigger on postgres to notify us of changes, which needs DDL privileges.
+    let no_sync_env = std::env::var("BOORU_DB_NO_SYNC").is_ok_and(|v| !v.is_empty() && v != "0");
+    let sync = !no_sync_env && !std::env::args().any(|arg| arg == "--no-sync");
+    let uri = std::env::args().nth(1).unwrap();
+    let pool = sqlx::PgPool::connect(&uri).await.unwrap();
+    let meta_pool = match arg_value("--meta-db").or_else(|| std::env::var("BOORU_DB_META").ok()) {
+        Some(meta_uri) => sqlx::PgPool::connect(&meta_uri).await.unwrap(),
+        None => pool.clone(),
+    };
+    // Fetched alongside the posts, they're only needed once every post is in.
+    let users = {
+        let meta_pool = meta_pool.clone();
+        tokio::spawn(async move { fetch_users(&meta_pool).await.unwrap() })
+    };
+    let tag_categories = {
+        let meta_pool = meta_pool.clone();
+        tokio::spawn(async move { fetch_tag_categories(&meta_pool).await.unwrap() })
+    };
+
+    let admin_token = std::env::var("BOORU_DB_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .map(Arc::from);
+    let score_floor =
+        arg_value("--score-floor").or_else(|| std::env::var("BOORU_DB_SCORE_FLOOR").ok());
+    let score_floor = match score_floor.as_deref() {
+        None | Some("off") => ScoreFloor(None),
+        Some(floor) => {
+            ScoreFloor(Some(floor.parse().unwrap_or_else(|error| {
+                panic!("invalid score floor {floor:?}: {error}")
+            })))
+        }
+    };
+
+    let pools = Pools {
+        posts: pool.clone(),
+        meta: meta_pool,
+    };
+    let empty = load_db(Vec::new(), Default::default(), Default::default(), &config);
+    let db = Arc::new(RwLock::new(empty));
+    let query_cache = Arc::new(QueryCache::new(
+        config.query_cache_capacity,
+        QUERY_CACHE_MAX_IDS,
+    ));
+    let sync_lag = Arc::new(AtomicI64::new(0));
+    let progress = Arc::new(LoadProgress::default());
+    let (live, _) = broadcast::channel(LIVE_CAPACITY);
+    let sync_lock = SyncLock::default();
+
+    let queries = Router::new()
+        .route(
+            "/posts",
+            get(get_posts).post(post_posts).layer(from_fn(access_log)),
+        )
+        .route("/posts/random", get(get_random_posts))
+        .route("/posts/similar", get(get_similar_posts))
+        .route("/stats", get(get_stats))
+        .route("/stats/histogram", get(get_histogram))
+        .route("/tags", get(get_tags).layer(from_fn(access_log)))
+        .route("/tags/autocomplete", get(get_tags_autocomplete))
+        .route("/tags/:name", get(get_tag_detail))
+        .route("/users", get(get_users))
+        .route_layer(from_fn_with_state(progress.clone(), require_ready));
+    let app = Router::new()
+        .route("/admin/compact", post(post_compact))
+        .route("/admin/reload", post(post_reload))
+        .route("/admin/verify", post(post_verify))
+        .route("/health", get(get_health))
+        .route("/live", get(get_live))
+        .merge(queries)
+        .with_state(AppState {
+            db: db.clone(),
+            query_cache: query_cache.clone(),
+            sync_lag: sync_lag.clone(),
+            pools: pools.clone(),
+            admin_token,
+            progress: progress.clone(),
+            score_floor,
+            config,
+            live: live.clone(),
+            sync_lock: sync_lock.clone(),
+        });
+    let server = tokio::spawn(
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                let _ = tokio::signal::ctrl_c().await;
+                info!("shutting down");
+            }),
+    );
+
+    let (tx, rx) = sync_channel::<BooruPost>(1024);
+    let load_progress = progress.clone();
+    let final_snapshot = snapshot.clone();
+    let listener_uri = uri.clone();
+    let pg_listener = tokio::spawn(async move {
+        let listener = if sync {
+            Some(create_listener(&uri, &pool).await)
+        } else {
+            None
+        };
+
+        let cached = snapshot
+            .as_deref()
+            .filter(|path| path.exists())
+            .and_then(|path| match read_snapshot(path) {
+                Ok(posts) => Some(posts),
+                Err(error) => {
+                    warn!(%error, "ignoring unreadable snapshot");
+                    None
+                }
+            });
+        let mut writer = snapshot
+            .as_deref()
+            .map(|path| SnapshotWriter::create(path).unwrap());
+        let mut count = 0;
+        let mut send = |post: BooruPost| {
+            if let Some(writer) = &mut writer {
+                writer.write(&post).unwrap();
+            }
+            tx.send(post).unwrap();
+            load_progress.loaded.fetch_add(1, Ordering::Relaxed);
+            count += 1;
+            if count % 50_000 == 0 {
+                debug!(count, "loading posts");
+            }
+        };
+
+        if let Some(cached) = cached {
+            // Only rows touched since the newest cached post need to be fetched again.
+            // This covers both edits and posts created after the snapshot was written,
+            // posts deleted upstream in the meantime are not detected.
+            let mut changed = fxhash::FxHashMap::default();
+            if let Some(watermark) = cached.iter().map(|post| post.updated_at).max() {
+                let mut posts =
+                    sqlx::query_as::<_, RawBooruPost>("SELECT * FROM posts WHERE updated_at > $1")
+                        .bind(watermark)
+                        .fetch(&pool);
+                while let Some(post) = posts.next().await {
+                    let post = post.expect("failed to fetch changed posts");
+                    if let Some(post) = BooruPost::from_row(post) {
+                        changed.insert(post.id, post);
+                    }
+                }
+            }
+            for post in cached {
+                send(changed.remove(&post.id).unwrap_or(post));
+            }
+            let mut created: Vec<_> = changed.into_values().collect();
+            created.sort_unstable_by_key(|post| post.id);
+            created.into_iter().for_each(&mut send);
+        } else {
+            let mut posts = sqlx::query_as::<_, RawBooruPost>("SELECT * FROM posts").fetch(&pool);
+            while let Some(post) = posts.next().await {
+                let post = post.expect("failed to fetch posts");
+                if let Some(post) = BooruPost::from_row(post) {
+                    send(post);
+                }
+            }
+        }
+        // Only reached once every row was scanned, a failed scan leaves the old snapshot alone.
+        if let Some(writer) = writer {
+            writer.finish().unwrap();
+        }
+
+        listener
+    });
+
+    let start_time = Instant::now();
+    let posts: Vec<BooruPost> = rx.iter().collect();
+    // A failed scan ends the channel early too, never index what it got that far.
+    let pg_listener = pg_listener.await.unwrap();
+    // Later conversions only come from sync, no need to keep every tag name around for them.
+    info!(tags = intern::tag_names().interned(), "interned tag names");
+    intern::tag_names().clear();
+    let users = users.await.unwrap();
+    let tag_categories = tag_categories.await.unwrap();
+    let loaded = load_db(posts, users, tag_categories, &config);
     let elapsed = start_time.elapsed().as_nanos();
-    println!("Index: {:.3}s", elapsed as f64 / 1000.0 / 1000.0 / 1000.0);
+    info!("Index: {:.3}s", elapsed as f64 / 1000.0 / 1000.0 / 1000.0);
 
-    let db = Arc::new(RwLock::new(db));
-    if let Some(pg_listener) = pg_listener.await.unwrap() {
+    {
+        let mut db = db.write().await;
+        *db = loaded;
+        query_cache.invalidate();
+    }
+    progress.ready.store(true, Ordering::Relaxed);
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let listener_task = pg_listener.map(|pg_listener| {
+        let pool = pools.posts.clone();
         let db = db.clone();
         tokio::spawn(async move {
-            handle_listener(db, pg_listener).await;
-        });
+            handle_listener(
+                db,
+                query_cache,
+                sync_lag,
+                live,
+                pool,
+                listener_uri,
+                sync_lock,
+                pg_listener,
+                shutdown_rx,
+            )
+            .await;
+        })
+    });
+
+    let _ = server.await;
+
+    let _ = shutdown_tx.send(true);
+    if let Some(listener_task) = listener_task {
+        let _ = listener_task.await;
     }
+    // Written after sync drained so the next startup only fetches rows changed from here on.
+    if let Some(path) = final_snapshot {
+        let written = tokio::task::spawn_blocking(move || {
+            let db = db.blocking_read();
+            let post_index: &PostIndex = db.index().unwrap();
+            write_snapshot(&path, post_index.posts())
+        })
+        .await
+        .unwrap();
+        match written {
+            Ok(()) => info!("wrote snapshot"),
+            Err(error) => warn!(%error, "failed to write snapshot"),
+        }
+    }
+}
 
-    let app = Router::new()
-        .route("/posts", get(get_posts))
-        .route("/tags", get(get_tags))
-        .with_state(db.clone());
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    let _ = axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::query::parse_query;
+
+    // A Db over `posts` as loaded on startup, without users or tag categories.
+    pub fn db(posts: Vec<BooruPost>) -> Db {
+        load_db(
+            posts,
+            Default::default(),
+            Default::default(),
+            &Config::default(),
+        )
+    }
+
+    // Post ids matching `text` in ascending order, without the `/posts` defaults.
+    pub fn search(db: &Db, text: &str) -> Vec<u32> {
+        matches(db, &parse_query(text).unwrap())
+    }
+
+    pub fn matches(db: &Db, query: &booru_db::Query<String>) -> Vec<u32> {
+        let result = db.query(query).unwrap();
+        let id_index: &IdIndex = db.index().unwrap();
+        let mut post_ids: Vec<_> = result
+            .get(0, result.matched(), false)
+            .into_iter()
+            .map(|id| id_index.id_to_post_id(id).unwrap())
+            .collect();
+        post_ids.sort_unstable();
+        post_ids
+    }
 }
@@ -1,33 +1,51 @@
 use std::{str::FromStr, sync::Arc};
 
 use chrono::NaiveDateTime;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use tracing::warn;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+use crate::{
+    error::{ParseError, RowError},
+    intern,
+};
+
+// Ordered from safest to most explicit so `rating:>=q` style range queries work.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 pub enum Rating {
-    G,
-    S,
-    Q,
-    E,
+    G = 0,
+    S = 1,
+    Q = 2,
+    E = 3,
+}
+
+impl Rating {
+    pub const ALL: [Self; 4] = [Self::G, Self::S, Self::Q, Self::E];
 }
 
 impl FromStr for Rating {
-    type Err = ();
+    type Err = ParseError;
 
+    // Letters and danbooru's long names. `s` is sensitive, `safe` is the name `g` had before
+    // danbooru split it into general and sensitive, so it maps to general.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "g" => Ok(Self::G),
-            "s" => Ok(Self::S),
-            "q" => Ok(Self::Q),
-            "e" => Ok(Self::E),
-            _ => Err(()),
+        match s {
+            s if s.eq_ignore_ascii_case("g") => Ok(Self::G),
+            s if s.eq_ignore_ascii_case("general") => Ok(Self::G),
+            s if s.eq_ignore_ascii_case("safe") => Ok(Self::G),
+            s if s.eq_ignore_ascii_case("s") => Ok(Self::S),
+            s if s.eq_ignore_ascii_case("sensitive") => Ok(Self::S),
+            s if s.eq_ignore_ascii_case("q") => Ok(Self::Q),
+            s if s.eq_ignore_ascii_case("questionable") => Ok(Self::Q),
+            s if s.eq_ignore_ascii_case("e") => Ok(Self::E),
+            s if s.eq_ignore_ascii_case("explicit") => Ok(Self::E),
+            _ => Err(ParseError::UnknownKeyword),
         }
     }
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum FileExt {
     AVIF,
     BMP,
@@ -39,29 +57,67 @@ pub enum FileExt {
     WEBM,
     WEBP,
     ZIP,
+    // Extensions added upstream that aren't known here yet, lowercased.
+    // `file_ext:jxl` matches one of them and `file_ext:unknown` all of them.
+    Other(Box<str>),
+}
+
+impl FileExt {
+    // Lowercase like danbooru stores it.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::AVIF => "avif",
+            Self::BMP => "bmp",
+            Self::GIF => "gif",
+            Self::JPG => "jpg",
+            Self::MP4 => "mp4",
+            Self::PNG => "png",
+            Self::SWF => "swf",
+            Self::WEBM => "webm",
+            Self::WEBP => "webp",
+            Self::ZIP => "zip",
+            Self::Other(ext) => ext,
+        }
+    }
+
+    pub const ALL: [Self; 10] = [
+        Self::AVIF,
+        Self::BMP,
+        Self::GIF,
+        Self::JPG,
+        Self::MP4,
+        Self::PNG,
+        Self::SWF,
+        Self::WEBM,
+        Self::WEBP,
+        Self::ZIP,
+    ];
 }
 
 impl FromStr for FileExt {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "avif" => Ok(Self::AVIF),
-            "bmp" => Ok(Self::BMP),
-            "gif" => Ok(Self::GIF),
-            "jpg" => Ok(Self::JPG),
-            "png" => Ok(Self::PNG),
-            "mp4" => Ok(Self::MP4),
-            "swf" => Ok(Self::SWF),
-            "webm" => Ok(Self::WEBM),
-            "webp" => Ok(Self::WEBP),
-            "zip" => Ok(Self::ZIP),
-            _ => Err(()),
+        match s {
+            s if s.eq_ignore_ascii_case("avif") => Ok(Self::AVIF),
+            s if s.eq_ignore_ascii_case("bmp") => Ok(Self::BMP),
+            s if s.eq_ignore_ascii_case("gif") => Ok(Self::GIF),
+            s if s.eq_ignore_ascii_case("jpg") => Ok(Self::JPG),
+            s if s.eq_ignore_ascii_case("png") => Ok(Self::PNG),
+            s if s.eq_ignore_ascii_case("mp4") => Ok(Self::MP4),
+            s if s.eq_ignore_ascii_case("swf") => Ok(Self::SWF),
+            s if s.eq_ignore_ascii_case("webm") => Ok(Self::WEBM),
+            s if s.eq_ignore_ascii_case("webp") => Ok(Self::WEBP),
+            s if s.eq_ignore_ascii_case("zip") => Ok(Self::ZIP),
+            s if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                Ok(Self::Other(s.to_ascii_lowercase().into()))
+            }
+            _ => Err(ParseError::UnknownKeyword),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Status {
     Active,
     Banned,
@@ -71,21 +127,60 @@ pub enum Status {
 }
 
 impl FromStr for Status {
-    type Err = ();
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("active") => Ok(Self::Active),
+            s if s.eq_ignore_ascii_case("banned") => Ok(Self::Banned),
+            s if s.eq_ignore_ascii_case("deleted") => Ok(Self::Deleted),
+            s if s.eq_ignore_ascii_case("flagged") => Ok(Self::Flagged),
+            s if s.eq_ignore_ascii_case("pending") => Ok(Self::Pending),
+            _ => Err(ParseError::UnknownKeyword),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum TagCategory {
+    #[default]
+    General,
+    Artist,
+    Copyright,
+    Character,
+    Meta,
+}
+
+impl TagCategory {
+    // Value of the `category` column in danbooru's tags table.
+    pub fn from_id(id: i32) -> Option<Self> {
+        match id {
+            0 => Some(Self::General),
+            1 => Some(Self::Artist),
+            3 => Some(Self::Copyright),
+            4 => Some(Self::Character),
+            5 => Some(Self::Meta),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TagCategory {
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "active" => Ok(Self::Active),
-            "banned" => Ok(Self::Banned),
-            "deleted" => Ok(Self::Deleted),
-            "flagged" => Ok(Self::Flagged),
-            "pending" => Ok(Self::Pending),
-            _ => Err(()),
+        match s {
+            s if s.eq_ignore_ascii_case("general") => Ok(Self::General),
+            s if s.eq_ignore_ascii_case("artist") => Ok(Self::Artist),
+            s if s.eq_ignore_ascii_case("copyright") => Ok(Self::Copyright),
+            s if s.eq_ignore_ascii_case("character") => Ok(Self::Character),
+            s if s.eq_ignore_ascii_case("meta") => Ok(Self::Meta),
+            _ => Err(ParseError::UnknownKeyword),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BooruPost {
     pub id: u32,
     pub parent_id: Option<u32>,
@@ -109,6 +204,9 @@ pub struct BooruPost {
     pub file_size: u32,
 
     pub rating: Rating,
+    pub is_note_locked: bool,
+    pub pools: Vec<u32>,
+    pub favs: Vec<u32>,
 
     pub tags: Vec<Arc<str>>,
     pub tag_count_general: u16,
@@ -116,6 +214,69 @@ pub struct BooruPost {
     pub tag_count_character: u16,
     pub tag_count_copyright: u16,
     pub tag_count_meta: u16,
+
+    pub bit_flags: u64,
+    pub last_noted_at: Option<NaiveDateTime>,
+    pub last_commented_at: Option<NaiveDateTime>,
+}
+
+impl BooruPost {
+    // Inverse of `From<RawBooruPost>`, shaped like danbooru's `/posts.json` with the same
+    // field names. `tag_string_*` are split using `category`, unknown tags count as general.
+    pub fn to_raw_json(&self, category: impl Fn(&str) -> TagCategory) -> serde_json::Value {
+        let join = |only: Option<TagCategory>| {
+            self.tags
+                .iter()
+                .filter(|tag| only.is_none() || only == Some(category(tag)))
+                .map(|tag| &**tag)
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let pool_string: Vec<_> = self.pools.iter().map(|id| format!("pool:{id}")).collect();
+        let fav_string: Vec<_> = self.favs.iter().map(|id| format!("fav:{id}")).collect();
+        let timestamp = |time: NaiveDateTime| time.and_utc().to_rfc3339();
+        serde_json::json!({
+            "id": self.id,
+            "parent_id": self.parent_id,
+            "pixiv_id": self.pixiv_id,
+            "uploader_id": self.uploader_id,
+            "approver_id": self.approver_id,
+            "is_banned": self.status == Status::Banned,
+            "is_deleted": self.status == Status::Deleted,
+            "is_flagged": self.status == Status::Flagged,
+            "is_pending": self.status == Status::Pending,
+            "created_at": timestamp(self.created_at),
+            "updated_at": timestamp(self.updated_at),
+            "fav_count": self.fav_count,
+            "score": self.up_score + self.down_score,
+            "up_score": self.up_score,
+            "down_score": self.down_score,
+            "source": self.source,
+            "image_width": self.width,
+            "image_height": self.height,
+            "file_ext": self.file_ext.as_str(),
+            "file_size": self.file_size,
+            "rating": format!("{:?}", self.rating).to_lowercase(),
+            "is_note_locked": self.is_note_locked,
+            "pool_string": pool_string.join(" "),
+            "fav_string": fav_string.join(" "),
+            "tag_string": join(None),
+            "tag_string_general": join(Some(TagCategory::General)),
+            "tag_string_artist": join(Some(TagCategory::Artist)),
+            "tag_string_character": join(Some(TagCategory::Character)),
+            "tag_string_copyright": join(Some(TagCategory::Copyright)),
+            "tag_string_meta": join(Some(TagCategory::Meta)),
+            "tag_count": self.tags.len(),
+            "tag_count_general": self.tag_count_general,
+            "tag_count_artist": self.tag_count_artist,
+            "tag_count_character": self.tag_count_character,
+            "tag_count_copyright": self.tag_count_copyright,
+            "tag_count_meta": self.tag_count_meta,
+            "bit_flags": self.bit_flags,
+            "last_noted_at": self.last_noted_at.map(timestamp),
+            "last_commented_at": self.last_commented_at.map(timestamp),
+        })
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, FromRow)]
@@ -145,6 +306,9 @@ pub struct RawBooruPost {
     pub file_size: i32,
 
     pub rating: String,
+    pub is_note_locked: bool,
+    pub pool_string: String,
+    pub fav_string: String,
 
     pub tag_string: String,
     pub tag_count_general: i32,
@@ -152,11 +316,52 @@ pub struct RawBooruPost {
     pub tag_count_character: i32,
     pub tag_count_copyright: i32,
     pub tag_count_meta: i32,
+
+    pub bit_flags: i64,
+    pub last_noted_at: Option<NaiveDateTime>,
+    pub last_commented_at: Option<NaiveDateTime>,
 }
 
-impl From<RawBooruPost> for BooruPost {
-    fn from(raw: RawBooruPost) -> Self {
-        Self {
+// Checked conversion of a column, instead of wrapping into a bogus value.
+fn fit<T: TryFrom<i32>>(post_id: i32, field: &'static str, value: i32) -> Result<T, RowError> {
+    T::try_from(value).map_err(|_| RowError {
+        post_id,
+        field,
+        value: value.to_string(),
+        reason: ParseError::OutOfRange,
+    })
+}
+
+impl BooruPost {
+    // None for rows that don't fit, logged so they can be fixed upstream.
+    pub fn from_row(raw: RawBooruPost) -> Option<Self> {
+        Self::try_from(raw)
+            .map_err(|error| warn!(%error, "skipping post"))
+            .ok()
+    }
+}
+
+impl TryFrom<RawBooruPost> for BooruPost {
+    type Error = RowError;
+
+    fn try_from(raw: RawBooruPost) -> Result<Self, Self::Error> {
+        let width = fit(raw.id, "image_width", raw.image_width)?;
+        let height = fit(raw.id, "image_height", raw.image_height)?;
+        let file_size = fit(raw.id, "file_size", raw.file_size)?;
+        // Same parse as `file_ext:` queries, a value that couldn't be queried isn't stored.
+        let file_ext = raw.file_ext.parse().map_err(|reason| RowError {
+            post_id: raw.id,
+            field: "file_ext",
+            value: raw.file_ext.clone(),
+            reason,
+        })?;
+        let rating = raw.rating.parse().map_err(|reason| RowError {
+            post_id: raw.id,
+            field: "rating",
+            value: raw.rating.clone(),
+            reason,
+        })?;
+        Ok(Self {
             id: raw.id as u32,
             parent_id: raw.parent_id.map(|i| i as u32),
             pixiv_id: raw.pixiv_id.map(|i| i as u32),
@@ -179,21 +384,154 @@ impl From<RawBooruPost> for BooruPost {
             up_score: raw.up_score,
             down_score: raw.down_score,
             source: raw.source,
-            width: raw.image_width as u16,
-            height: raw.image_height as u16,
-            file_ext: raw.file_ext.parse().unwrap(),
-            file_size: raw.file_size as u32,
-            rating: raw.rating.parse().unwrap(),
+            width,
+            height,
+            file_ext,
+            file_size,
+            rating,
+            is_note_locked: raw.is_note_locked,
+            pools: raw
+                .pool_string
+                .split_whitespace()
+                .filter_map(|p| p.strip_prefix("pool:")?.parse().ok())
+                .collect(),
+            favs: raw
+                .fav_string
+                .split_whitespace()
+                .filter_map(|f| f.strip_prefix("fav:")?.parse().ok())
+                .collect(),
             tags: raw
                 .tag_string
                 .split_whitespace()
-                .map(|t| t.to_string().into())
+                .map(|t| intern::tag_names().intern(t))
                 .collect(),
             tag_count_general: raw.tag_count_general as u16,
             tag_count_artist: raw.tag_count_artist as u16,
             tag_count_character: raw.tag_count_character as u16,
             tag_count_copyright: raw.tag_count_copyright as u16,
             tag_count_meta: raw.tag_count_meta as u16,
+            bit_flags: raw.bit_flags as u64,
+            last_noted_at: raw.last_noted_at,
+            last_commented_at: raw.last_commented_at,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    // A plain active post, tests change the columns they care about.
+    pub fn raw(id: i32) -> RawBooruPost {
+        let time = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        RawBooruPost {
+            id,
+            parent_id: None,
+            pixiv_id: None,
+            uploader_id: 1,
+            approver_id: None,
+            is_banned: false,
+            is_deleted: false,
+            is_flagged: false,
+            is_pending: false,
+            created_at: time,
+            updated_at: time,
+            fav_count: 0,
+            up_score: 0,
+            down_score: 0,
+            source: String::new(),
+            image_width: 1000,
+            image_height: 1000,
+            file_ext: "jpg".into(),
+            file_size: 100_000,
+            rating: "g".into(),
+            is_note_locked: false,
+            pool_string: String::new(),
+            fav_string: String::new(),
+            tag_string: String::new(),
+            tag_count_general: 0,
+            tag_count_artist: 0,
+            tag_count_character: 0,
+            tag_count_copyright: 0,
+            tag_count_meta: 0,
+            bit_flags: 0,
+            last_noted_at: None,
+            last_commented_at: None,
+        }
+    }
+
+    pub fn post(id: i32) -> BooruPost {
+        BooruPost::try_from(raw(id)).unwrap()
+    }
+
+    #[test]
+    fn wide_row_is_skipped() {
+        let row = RawBooruPost {
+            image_width: 70_000,
+            ..raw(1)
+        };
+        let error = BooruPost::try_from(row.clone()).unwrap_err();
+        assert_eq!(error.field, "image_width");
+        assert_eq!(error.value, "70000");
+        assert_eq!(error.reason, ParseError::OutOfRange);
+        assert!(BooruPost::from_row(row).is_none());
+        assert_eq!(post(2).width, 1000);
+    }
+
+    #[test]
+    fn unknown_rating_is_skipped() {
+        let row = RawBooruPost {
+            rating: "x".into(),
+            ..raw(1)
+        };
+        let error = BooruPost::try_from(row.clone()).unwrap_err();
+        assert_eq!(error.field, "rating");
+        assert_eq!(error.reason, ParseError::UnknownKeyword);
+        assert!(BooruPost::from_row(row).is_none());
+    }
+
+    #[test]
+    fn unqueryable_file_ext_is_skipped() {
+        let ext = |file_ext: &str| {
+            BooruPost::try_from(RawBooruPost {
+                file_ext: file_ext.into(),
+                ..raw(1)
+            })
+            .map(|post| post.file_ext)
+        };
+        assert_eq!(ext("PNG"), Ok(FileExt::PNG));
+        assert_eq!(ext("JXL"), Ok(FileExt::Other("jxl".into())));
+        for file_ext in ["", "tar.gz", " jpg", "jpé"] {
+            let error = ext(file_ext).unwrap_err();
+            assert_eq!(error.field, "file_ext");
+            assert_eq!(error.reason, ParseError::UnknownKeyword);
         }
     }
+
+    #[test]
+    fn keywords_ignore_ascii_case_only() {
+        assert_eq!("Explicit".parse(), Ok(Rating::E));
+        assert_eq!("gEnErAl".parse(), Ok(Rating::G));
+        assert_eq!("DELETED".parse(), Ok(Status::Deleted));
+        assert_eq!("Artist".parse(), Ok(TagCategory::Artist));
+        assert_eq!("JPG".parse(), Ok(FileExt::JPG));
+        assert_eq!("JXL".parse(), Ok(FileExt::Other("jxl".into())));
+        // Unicode case folding would turn these into ascii keywords.
+        assert_eq!(
+            "\u{212a}".parse::<Rating>(),
+            Err(ParseError::UnknownKeyword)
+        );
+        assert_eq!("ｅ".parse::<Rating>(), Err(ParseError::UnknownKeyword));
+        assert_eq!("actİve".parse::<Status>(), Err(ParseError::UnknownKeyword));
+        assert_eq!("ｊｐｇ".parse::<FileExt>(), Err(ParseError::UnknownKeyword));
+        assert_eq!(
+            "méta".parse::<TagCategory>(),
+            Err(ParseError::UnknownKeyword)
+        );
+    }
 }
@@ -0,0 +1,312 @@
+use std::{
+    fmt::Debug,
+    sync::{atomic::AtomicI64, Arc},
+    time::Instant,
+};
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use futures::{future, TryStreamExt};
+use fxhash::FxHashMap;
+use serde::Serialize;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{info, warn};
+
+use crate::{
+    cache::QueryCache,
+    config::Config,
+    fetch_tag_categories, fetch_users,
+    index::{
+        CreatedAtIndex, FavCountIndex, HeightIndex, IdIndex, PostIndex, ScoreIndex,
+        TagDbCountIndex, TagDbIdIndex, TagIndex, UpdatedAtIndex, WidthIndex,
+    },
+    intern, load_db,
+    post::RawBooruPost,
+    routes::ApiError,
+    sync::{catch_up, last_updated_at, LiveEvent, SyncLock},
+    BooruPost, Db, Pools,
+};
+
+// Admin routes need `Authorization: Bearer <token>` matching `BOORU_DB_ADMIN_TOKEN`,
+// without the env var they are refused entirely.
+fn authorize(headers: &HeaderMap, admin_token: &Option<Arc<str>>) -> Result<(), ApiError> {
+    let Some(admin_token) = admin_token else {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin_disabled",
+            "admin routes are disabled",
+        ));
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if token == &**admin_token => Ok(()),
+        _ => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "invalid admin token",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct CompactResponse {
+    tags_before: usize,
+    tags_after: usize,
+}
+
+// Rebuilds the tag db, blocking every query while it runs.
+// Post ids are untouched so cached query results stay valid.
+pub async fn post_compact(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(admin_token): State<Option<Arc<str>>>,
+    headers: HeaderMap,
+) -> Result<Json<CompactResponse>, ApiError> {
+    authorize(&headers, &admin_token)?;
+
+    let mut db = db.write().await;
+    let tag_index: &mut TagIndex = db.index_mut().unwrap();
+    info!(tags = tag_index.keys_index.items.len(), "compacting tag db");
+    let (tags_before, tags_after) = tag_index.compact();
+    drop(db);
+    info!(tags_before, tags_after, "compacted tag db");
+
+    let response = CompactResponse {
+        tags_before,
+        tags_after,
+    };
+    Ok(response.into())
+}
+
+#[derive(Serialize)]
+pub struct ReloadResponse {
+    posts: usize,
+    // Nanoseconds spent scanning and indexing, queries are only blocked for the swap.
+    elapsed: u64,
+}
+
+// Scans every post again and swaps in a freshly built Db, for when sync has drifted.
+#[allow(clippy::too_many_arguments)]
+pub async fn post_reload(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(query_cache): State<Arc<QueryCache>>,
+    State(sync_lag): State<Arc<AtomicI64>>,
+    State(live): State<broadcast::Sender<LiveEvent>>,
+    State(sync_lock): State<SyncLock>,
+    State(pools): State<Pools>,
+    State(config): State<Config>,
+    State(admin_token): State<Option<Arc<str>>>,
+    headers: HeaderMap,
+) -> Result<Json<ReloadResponse>, ApiError> {
+    authorize(&headers, &admin_token)?;
+
+    info!("reloading posts");
+    let start_time = Instant::now();
+    // Sync keeps applying changes to the old Db during the scan, those are lost with it.
+    // Every row updated since is fetched again into the new Db once it's swapped in.
+    let since = last_updated_at(&*db.read().await);
+    let scan = async {
+        let users = fetch_users(&pools.meta).await?;
+        let tag_categories = fetch_tag_categories(&pools.meta).await?;
+        let posts: Vec<BooruPost> = sqlx::query_as::<_, RawBooruPost>("SELECT * FROM posts")
+            .fetch(&pools.posts)
+            .try_filter_map(|post| future::ready(Ok(BooruPost::from_row(post))))
+            .try_collect()
+            .await?;
+        sqlx::Result::Ok((posts, users, tag_categories))
+    };
+    let (posts, users, tag_categories) = scan.await.map_err(|error| {
+        warn!(%error, "reload scan failed");
+        ApiError::internal("reload scan failed")
+    })?;
+    intern::tag_names().clear();
+    let count = posts.len();
+    let new_db =
+        tokio::task::spawn_blocking(move || load_db(posts, users, tag_categories, &config))
+            .await
+            .map_err(|_| ApiError::internal("reload indexing failed"))?;
+
+    // Sync waits until the new Db caught up, otherwise it could apply changes the scan missed
+    // before the rows they're based on are fetched again. Queries only wait for the swap.
+    let sync = sync_lock.lock().await;
+    let old_db = {
+        let mut db = db.write().await;
+        query_cache.invalidate();
+        std::mem::replace(&mut *db, new_db)
+    };
+    // Dropping millions of posts takes a while, do it off the async runtime.
+    tokio::task::spawn_blocking(move || drop(old_db));
+    if let Some(since) = since {
+        match catch_up(&db, &query_cache, &sync_lag, &live, &pools.posts, since).await {
+            Ok(caught_up) => info!(caught_up, "reload caught up"),
+            Err(error) => warn!(%error, "reload catch up failed"),
+        }
+    }
+    drop(sync);
+    let elapsed = start_time.elapsed().as_nanos() as u64;
+    info!(posts = count, elapsed, "reloaded posts");
+
+    let response = ReloadResponse {
+        posts: count,
+        elapsed,
+    };
+    Ok(response.into())
+}
+
+// Posts checked per read lock, so syncing isn't blocked for the whole walk.
+const VERIFY_CHUNK: usize = 10_000;
+// Mismatches past this are only counted.
+const VERIFY_MAX_REPORTED: usize = 100;
+
+#[derive(Default, Serialize)]
+pub struct VerifyResponse {
+    posts: usize,
+    tags: usize,
+    mismatched: usize,
+    mismatches: Vec<String>,
+    elapsed: u64,
+}
+
+impl VerifyResponse {
+    fn check<T: Debug + PartialEq>(&mut self, what: &str, indexed: Option<T>, expected: T) {
+        if indexed.as_ref() == Some(&expected) {
+            return;
+        }
+        self.mismatched += 1;
+        if self.mismatches.len() < VERIFY_MAX_REPORTED {
+            let message = format!("{what}: indexed {indexed:?}, expected {expected:?}");
+            self.mismatches.push(message);
+        }
+    }
+}
+
+// Re-derives index values from the stored posts and reports where they disagree.
+// Posts are walked in chunks, a post synced between chunks can show up as a false mismatch.
+// Tag counts need every post at once and are checked under a single read lock.
+pub async fn post_verify(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(admin_token): State<Option<Arc<str>>>,
+    headers: HeaderMap,
+) -> Result<Json<VerifyResponse>, ApiError> {
+    authorize(&headers, &admin_token)?;
+
+    info!("verifying indices");
+    let start_time = Instant::now();
+    let mut response = VerifyResponse::default();
+    let ids: Vec<_> = {
+        let db = db.read().await;
+        let id_index: &IdIndex = db.index().unwrap();
+        let post_index: &PostIndex = db.index().unwrap();
+        let ids = id_index.range_index.ids().to_vec();
+        response.check("post count", Some(post_index.len()), ids.len());
+        ids
+    };
+
+    for chunk in ids.chunks(VERIFY_CHUNK) {
+        let db = db.read().await;
+        let id_index: &IdIndex = db.index().unwrap();
+        let post_index: &PostIndex = db.index().unwrap();
+        let score_index: &ScoreIndex = db.index().unwrap();
+        let fav_count_index: &FavCountIndex = db.index().unwrap();
+        let created_at_index: &CreatedAtIndex = db.index().unwrap();
+        let updated_at_index: &UpdatedAtIndex = db.index().unwrap();
+        let width_index: &WidthIndex = db.index().unwrap();
+        let height_index: &HeightIndex = db.index().unwrap();
+        for &id in chunk {
+            // Removed since the ids were collected.
+            let Some(post) = post_index.get(id) else {
+                continue;
+            };
+            response.posts += 1;
+            let post_id = post.id;
+            response.check(
+                &format!("post {post_id} id"),
+                id_index.post_id_to_id(post_id),
+                id,
+            );
+            response.check(
+                &format!("post {post_id} score"),
+                score_index.range_index.id_values().get(&id).copied(),
+                post.up_score + post.down_score,
+            );
+            response.check(
+                &format!("post {post_id} favcount"),
+                fav_count_index.range_index.id_values().get(&id).copied(),
+                post.fav_count,
+            );
+            response.check(
+                &format!("post {post_id} created_at"),
+                created_at_index.range_index.id_values().get(&id).copied(),
+                post.created_at.timestamp_millis(),
+            );
+            response.check(
+                &format!("post {post_id} updated_at"),
+                updated_at_index.range_index.id_values().get(&id).copied(),
+                post.updated_at.timestamp_millis(),
+            );
+            response.check(
+                &format!("post {post_id} width"),
+                width_index.range_index.id_values().get(&id).copied(),
+                post.width,
+            );
+            response.check(
+                &format!("post {post_id} height"),
+                height_index.range_index.id_values().get(&id).copied(),
+                post.height,
+            );
+        }
+    }
+
+    {
+        let db = db.read().await;
+        let id_index: &IdIndex = db.index().unwrap();
+        let post_index: &PostIndex = db.index().unwrap();
+        let mut counts: FxHashMap<Arc<str>, u32> = FxHashMap::default();
+        for &id in id_index.range_index.ids() {
+            let Some(post) = post_index.get(id) else {
+                continue;
+            };
+            for tag in &post.tags {
+                *counts.entry(tag.clone()).or_default() += 1;
+            }
+        }
+        let tag_index: &TagIndex = db.index().unwrap();
+        let tag_id_index: &TagDbIdIndex = tag_index.tag_db.index().unwrap();
+        let tag_count_index: &TagDbCountIndex = tag_index.tag_db.index().unwrap();
+        // Tags left behind with no posts show up as extra entries here.
+        response.check(
+            "tag db size",
+            Some(tag_id_index.name_to_id.len()),
+            counts.len(),
+        );
+        for (name, count) in counts {
+            response.tags += 1;
+            let indexed = tag_index.keys_index.items.get(&name);
+            response.check(
+                &format!("tag {name} posts"),
+                indexed.map(|queryable| queryable.matched() as u32),
+                count,
+            );
+            let tag_id = tag_id_index.name_to_id.get(&name);
+            response.check(
+                &format!("tag {name} count"),
+                tag_id.and_then(|id| tag_count_index.range_index.id_values().get(id).copied()),
+                count,
+            );
+        }
+    }
+
+    response.elapsed = start_time.elapsed().as_nanos() as u64;
+    info!(
+        posts = response.posts,
+        tags = response.tags,
+        mismatched = response.mismatched,
+        "verified indices"
+    );
+    Ok(response.into())
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::post::{BooruPost, FileExt, Rating, Status};
+
+// 5452595 -> "5.2 MB", binary units like danbooru.
+fn pretty_bytes(bytes: u32) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+// Post as returned with `fields=full`, with friendlier renderings next to the raw values.
+#[derive(Serialize)]
+pub struct PostDto {
+    id: u32,
+    parent_id: Option<u32>,
+    pixiv_id: Option<u32>,
+    uploader_id: u32,
+    approver_id: Option<u32>,
+    status: Status,
+    created_at: String,
+    updated_at: String,
+    fav_count: u32,
+    score: i32,
+    up_score: i32,
+    down_score: i32,
+    source: String,
+    width: u16,
+    height: u16,
+    file_ext: FileExt,
+    file_size: u32,
+    file_size_pretty: String,
+    rating: Rating,
+    pools: Vec<u32>,
+    tags: Vec<Arc<str>>,
+}
+
+impl From<&BooruPost> for PostDto {
+    fn from(post: &BooruPost) -> Self {
+        Self {
+            id: post.id,
+            parent_id: post.parent_id,
+            pixiv_id: post.pixiv_id,
+            uploader_id: post.uploader_id,
+            approver_id: post.approver_id,
+            status: post.status,
+            created_at: post.created_at.and_utc().to_rfc3339(),
+            updated_at: post.updated_at.and_utc().to_rfc3339(),
+            fav_count: post.fav_count,
+            score: post.up_score + post.down_score,
+            up_score: post.up_score,
+            down_score: post.down_score,
+            source: post.source.clone(),
+            width: post.width,
+            height: post.height,
+            file_ext: post.file_ext.clone(),
+            file_size: post.file_size,
+            file_size_pretty: pretty_bytes(post.file_size),
+            rating: post.rating,
+            pools: post.pools.clone(),
+            tags: post.tags.clone(),
+        }
+    }
+}
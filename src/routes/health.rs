@@ -0,0 +1,47 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+
+use crate::{routes::ApiError, LoadProgress};
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    // Every post is indexed and queries see the whole Db.
+    ready: bool,
+    // Posts read from the snapshot or postgres so far.
+    loaded: usize,
+}
+
+pub async fn get_health(State(progress): State<Arc<LoadProgress>>) -> Json<HealthResponse> {
+    let response = HealthResponse {
+        ready: progress.ready.load(Ordering::Relaxed),
+        loaded: progress.loaded.load(Ordering::Relaxed),
+    };
+    response.into()
+}
+
+// Query routes answer 503 until every post is indexed, rather than results from a partial Db.
+pub async fn require_ready<B>(
+    State(progress): State<Arc<LoadProgress>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    if !progress.ready.load(Ordering::Relaxed) {
+        let loaded = progress.loaded.load(Ordering::Relaxed);
+        let error = ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "not_ready",
+            "still loading",
+        )
+        .with_detail(serde_json::json!({ "loaded": loaded }));
+        return Err(error);
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,40 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::sync::LiveEvent;
+
+// Streams `{"op": "insert" | "update" | "delete", "post_id": N}` for every synced change.
+pub async fn get_live(
+    State(live): State<broadcast::Sender<LiveEvent>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let events = live.subscribe();
+    upgrade.on_upgrade(move |socket| forward(socket, events))
+}
+
+// Subscribers too slow to keep up are told how many events they missed and disconnected,
+// they should refetch whatever they show.
+async fn forward(mut socket: WebSocket, mut events: broadcast::Receiver<LiveEvent>) {
+    loop {
+        let text = match events.recv().await {
+            Ok(event) => serde_json::to_string(&event).unwrap(),
+            Err(RecvError::Lagged(skipped)) => {
+                let lagged = json!({ "op": "lagged", "skipped": skipped });
+                let _ = socket.send(Message::Text(lagged.to_string())).await;
+                break;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.close().await;
+}
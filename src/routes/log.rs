@@ -0,0 +1,83 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{http::Request, middleware::Next, response::Response};
+use tracing::info;
+
+// Set by handlers as a response extension so the access log can report it.
+#[derive(Clone, Copy)]
+pub struct Matched(pub usize);
+
+// Access log events written per second, the rest are only counted.
+const ACCESS_LOG_PER_SECOND: u32 = 100;
+
+struct Window {
+    start: Option<Instant>,
+    logged: u32,
+    skipped: u32,
+}
+
+static WINDOW: Mutex<Window> = Mutex::new(Window {
+    start: None,
+    logged: 0,
+    skipped: 0,
+});
+
+// Whether to log this request, and how many were skipped in the previous second if it's
+// the first of a new one.
+fn admit() -> (bool, u32) {
+    let mut window = WINDOW.lock().unwrap();
+    let now = Instant::now();
+    let mut skipped = 0;
+    if window
+        .start
+        .map_or(true, |start| now - start >= Duration::from_secs(1))
+    {
+        skipped = window.skipped;
+        *window = Window {
+            start: Some(now),
+            logged: 0,
+            skipped: 0,
+        };
+    }
+    if window.logged < ACCESS_LOG_PER_SECOND {
+        window.logged += 1;
+        return (true, skipped);
+    }
+    window.skipped += 1;
+    (false, skipped)
+}
+
+// Logs the query string, matched count and total latency of each request as one event.
+// Per stage timings stay in the handlers' responses.
+pub async fn access_log<B>(request: Request<B>, next: Next<B>) -> Response {
+    let start_time = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let response = next.run(request).await;
+    let elapsed = start_time.elapsed().as_nanos() as u64;
+
+    let (admitted, skipped) = admit();
+    if skipped > 0 {
+        info!(target: "access", skipped, "access log rate limited");
+    }
+    if admitted {
+        let matched = response
+            .extensions()
+            .get::<Matched>()
+            .map(|matched| matched.0);
+        info!(
+            target: "access",
+            %method,
+            %path,
+            %query,
+            status = response.status().as_u16(),
+            matched,
+            elapsed,
+        );
+    }
+    response
+}
@@ -1,2 +1,137 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+pub mod admin;
+pub mod dto;
+pub mod health;
+pub mod live;
+pub mod log;
 pub mod posts;
+pub mod query;
+pub mod stats;
 pub mod tags;
+pub mod users;
+
+// Number of pages of `limit` results needed to show every match.
+pub fn total_pages(matched: usize, limit: usize) -> usize {
+    match limit {
+        0 => 0,
+        _ => matched.div_ceil(limit),
+    }
+}
+
+// Every error response, as `{"error_code": "bad_query", "message": ..., "detail": ...}`.
+// `error_code` is stable for clients to match on, `message` is for people.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    error_code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error_code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error_code,
+            message: message.into(),
+            detail: None,
+        }
+    }
+
+    // Malformed or conflicting parameters.
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    // A search query that doesn't parse, `detail` has the query text.
+    pub fn bad_query(query: &str, message: impl Into<String>) -> Self {
+        let detail = serde_json::json!({ "query": query });
+        Self::new(StatusCode::BAD_REQUEST, "bad_query", message).with_detail(detail)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+
+    pub fn with_detail(mut self, detail: serde_json::Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+// Like axum's `Query` but rejects malformed parameters with a 400 `ApiError`.
+pub struct Params<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Params<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(params)) => Ok(Params(params)),
+            Err(rejection) => {
+                let text = rejection.body_text();
+                let message = text
+                    .strip_prefix("Failed to deserialize query string: ")
+                    .unwrap_or(&text);
+                Err(ApiError::bad_request(message))
+            }
+        }
+    }
+}
+
+// Query strings only have text, JSON bodies have numbers.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Integer {
+    Number(i64),
+    Text(String),
+}
+
+fn non_negative<'de, D: Deserializer<'de>>(deserializer: D, name: &str) -> Result<usize, D::Error> {
+    let value = Integer::deserialize(deserializer).ok();
+    let value = match value {
+        Some(Integer::Number(number)) => usize::try_from(number).ok(),
+        Some(Integer::Text(text)) => text.trim().parse().ok(),
+        None => None,
+    };
+    value.ok_or_else(|| de::Error::custom(format!("{name} must be a non-negative integer")))
+}
+
+pub fn deserialize_limit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    non_negative(deserializer, "limit")
+}
+
+pub fn deserialize_page<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    non_negative(deserializer, "page")
+}
+
+pub fn deserialize_buckets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    non_negative(deserializer, "buckets")
+}
+
+pub fn deserialize_optional_page<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    deserialize_page(deserializer).map(Some)
+}
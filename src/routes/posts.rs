@@ -1,15 +1,36 @@
-use std::{sync::Arc, time::Instant};
+use std::{cmp::Ordering, collections::BinaryHeap, sync::Arc, time::Instant};
 
 use axum::{
-    extract::{Query as RQuery, State},
-    Json,
+    extract::{rejection::JsonRejection, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
-use booru_db::Query;
+use booru_db::{index::RangeIndex, ID};
+use fxhash::FxHashMap;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::sync::RwLock;
 
 use crate::{
-    index::{IdIndex, ScoreIndex},
+    cache::{self, QueryCache},
+    config::Config,
+    index::{
+        queryable_ids, take_query_error, AspectRatioIndex, CommentAtIndex, CreatedAtIndex,
+        FavCountIndex, FileSizeIndex, IdIndex, MPixelsIndex, NoteAtIndex, PopularityIndex,
+        PostIndex, ScoreIndex, TagIndex, UpdatedAtIndex,
+    },
+    post::BooruPost,
+    routes::{
+        deserialize_limit, deserialize_optional_page,
+        dto::PostDto,
+        log::Matched,
+        query::{
+            canonical, intersection, parse_posts_query, query_timed, tag_lists_ids, unmatched_tags,
+            QueryTree, ScoreFloor,
+        },
+        total_pages, ApiError, Params,
+    },
     Db,
 };
 
@@ -23,6 +44,56 @@ pub enum Sort {
     ScoreAsc,
     #[serde(alias = "score")]
     ScoreDesc,
+    FavAsc,
+    #[serde(alias = "favcount")]
+    FavDesc,
+    CreatedAsc,
+    #[serde(alias = "created_at")]
+    CreatedDesc,
+    UpdatedAsc,
+    #[serde(alias = "updated_at")]
+    UpdatedDesc,
+    FilesizeAsc,
+    #[serde(alias = "filesize")]
+    FilesizeDesc,
+    MpixelsAsc,
+    #[serde(alias = "mpixels")]
+    MpixelsDesc,
+    RatioAsc,
+    #[serde(alias = "ratio")]
+    RatioDesc,
+    #[serde(alias = "pop")]
+    PopDesc,
+    // Most recently commented or noted first, posts without any come last.
+    #[serde(alias = "comment")]
+    CommentDesc,
+    #[serde(alias = "note")]
+    NoteDesc,
+    // The order of the `ids` param.
+    Custom,
+    // Computed per query, see `rank`.
+    Rank,
+    // Computed per query from `seed`, see `shuffle`.
+    Random,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fields {
+    // Only the danbooru url listing the page's post ids.
+    #[default]
+    Ids,
+    // Every post on the page in full as well.
+    Full,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Default,
+    // A bare array of the page's posts as danbooru's `/posts.json` returns them.
+    Danbooru,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -31,85 +102,939 @@ pub struct GetPostsQuery {
     query: String,
     #[serde(default)]
     sort: Sort,
-
+    // Comma separated tags every post must have, ANDed with `query`.
     #[serde(default)]
-    page: usize,
-    #[serde(default = "posts_default_limit")]
+    tags_all: Option<String>,
+    // Comma separated tags of which posts must have at least one, ANDed with `query`.
+    #[serde(default)]
+    tags_any: Option<String>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_page")]
+    page: Option<usize>,
+    #[serde(
+        default = "posts_default_limit",
+        deserialize_with = "deserialize_limit"
+    )]
     limit: usize,
+    // Return posts with ids strictly below this instead of using `page`, only for `id_desc`.
+    #[serde(default)]
+    before_id: Option<u32>,
+    // Skip the full scan guard below.
+    #[serde(default)]
+    allow_full_scan: bool,
+    // Comma separated post ids for `sort=custom`, ids not matching the query are dropped.
+    #[serde(default)]
+    ids: Option<String>,
+    // Order for `sort=random`, picked and returned when missing. Reuse it to page through.
+    #[serde(default)]
+    seed: Option<u64>,
+    // Include per term timings in the response, evaluating the query term by term.
+    #[serde(default)]
+    debug: bool,
+    // Include the tags matching no posts in the response.
+    #[serde(default)]
+    explain: bool,
+    // Only parse the query and return it as a `QueryTree`, after simplifying.
+    #[serde(default)]
+    tree: bool,
+    // Include existing tags close to those matching no posts.
+    #[serde(default)]
+    suggest: bool,
+    #[serde(default)]
+    fields: Fields,
+    #[serde(default)]
+    format: Format,
 }
 
 const fn posts_default_limit() -> usize {
     20
 }
 
+// Corrections offered per unmatched tag with `suggest=true`.
+const SUGGESTIONS_PER_TAG: usize = 5;
+
 #[derive(Default, Serialize)]
 pub struct PostsResponseTimings {
     query: u64,
     sort: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    terms: Option<Vec<(String, u64)>>,
 }
 
 #[derive(Serialize)]
 pub struct PostsResponse {
     matched: usize,
-    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    page: usize,
+    total_pages: usize,
+    limit: usize,
+    max_limit: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    posts: Option<Vec<PostDto>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unmatched_tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestions: Option<Vec<(String, Vec<Arc<str>>)>>,
     timings: PostsResponseTimings,
 }
 
+// Ids of every post in sort order, whether the order should be walked backwards, and for range
+// index orders how to compare the values they're sorted by.
+fn sort_order<'d>(db: &'d Db, sort: &Sort, custom: &'d [ID]) -> SortOrder<'d> {
+    match sort {
+        // Post ids are unique, there are no ties to break.
+        Sort::IdAsc | Sort::IdDesc => {
+            let id_index: &IdIndex = db.index().unwrap();
+            (
+                id_index.range_index.ids(),
+                matches!(sort, Sort::IdDesc),
+                None,
+            )
+        }
+        Sort::ScoreAsc | Sort::ScoreDesc => {
+            let score_index: &ScoreIndex = db.index().unwrap();
+            by_value(&score_index.range_index, matches!(sort, Sort::ScoreDesc))
+        }
+        Sort::FavAsc | Sort::FavDesc => {
+            let fav_count_index: &FavCountIndex = db.index().unwrap();
+            by_value(&fav_count_index.range_index, matches!(sort, Sort::FavDesc))
+        }
+        Sort::CreatedAsc | Sort::CreatedDesc => {
+            let created_at_index: &CreatedAtIndex = db.index().unwrap();
+            by_value(
+                &created_at_index.range_index,
+                matches!(sort, Sort::CreatedDesc),
+            )
+        }
+        Sort::UpdatedAsc | Sort::UpdatedDesc => {
+            let updated_at_index: &UpdatedAtIndex = db.index().unwrap();
+            by_value(
+                &updated_at_index.range_index,
+                matches!(sort, Sort::UpdatedDesc),
+            )
+        }
+        Sort::FilesizeAsc | Sort::FilesizeDesc => {
+            let file_size_index: &FileSizeIndex = db.index().unwrap();
+            by_value(
+                &file_size_index.range_index,
+                matches!(sort, Sort::FilesizeDesc),
+            )
+        }
+        Sort::MpixelsAsc | Sort::MpixelsDesc => {
+            let mpixels_index: &MPixelsIndex = db.index().unwrap();
+            by_value(
+                &mpixels_index.range_index,
+                matches!(sort, Sort::MpixelsDesc),
+            )
+        }
+        Sort::RatioAsc | Sort::RatioDesc => {
+            let aspect_ratio_index: &AspectRatioIndex = db.index().unwrap();
+            by_value(
+                &aspect_ratio_index.range_index,
+                matches!(sort, Sort::RatioDesc),
+            )
+        }
+        Sort::PopDesc => {
+            let popularity_index: &PopularityIndex = db.index().unwrap();
+            by_value(&popularity_index.range_index, true)
+        }
+        Sort::CommentDesc => {
+            let comment_at_index: &CommentAtIndex = db.index().unwrap();
+            by_value(&comment_at_index.range_index, true)
+        }
+        Sort::NoteDesc => {
+            let note_at_index: &NoteAtIndex = db.index().unwrap();
+            by_value(&note_at_index.range_index, true)
+        }
+        Sort::Custom | Sort::Rank | Sort::Random => (custom, false, None),
+    }
+}
+
+type ValueCmp<'d> = Box<dyn Fn(ID, ID) -> Ordering + 'd>;
+type SortOrder<'d> = (&'d [ID], bool, Option<ValueCmp<'d>>);
+
+fn by_value<V: Ord>(range_index: &RangeIndex<V>, reverse: bool) -> SortOrder<'_> {
+    let values = range_index.id_values();
+    let cmp: ValueCmp = Box::new(move |a, b| values.get(&a).cmp(&values.get(&b)));
+    (range_index.ids(), reverse, Some(cmp))
+}
+
+// `limit` ids from `index` on of those `get_sorted` picks out of an order slice.
+// Range indices keep equal values in no particular order, which can change with every sync.
+// Ties the page shares with the pages around it are broken by post id so none of them overlap,
+// and a post keeps its place among them even if it reuses the id of a removed one.
+fn sorted_page(
+    id_index: &IdIndex,
+    (order, reverse, cmp): &SortOrder,
+    get_sorted: impl Fn(&[ID], usize, usize, bool) -> Vec<ID>,
+    index: usize,
+    limit: usize,
+) -> Vec<ID> {
+    let page = get_sorted(order, index, limit, *reverse);
+    let (Some(cmp), Some(&first), Some(&last)) = (cmp, page.first(), page.last()) else {
+        return page;
+    };
+    // Every id with a value the page has, with the matches walked before them counted.
+    let (low, high) = match reverse {
+        true => (last, first),
+        false => (first, last),
+    };
+    let start = order.partition_point(|&id| cmp(id, low).is_lt());
+    let end = order.partition_point(|&id| cmp(id, high).is_le());
+    let before = match reverse {
+        true => &order[end..],
+        false => &order[..start],
+    };
+    let before = get_sorted(before, 0, before.len(), false).len();
+    let span = &order[start..end];
+    let mut span = get_sorted(span, 0, span.len(), false);
+    let post_id = |id| id_index.id_to_post_id(id);
+    span.sort_unstable_by(|&a, &b| cmp(a, b).then_with(|| post_id(a).cmp(&post_id(b))));
+    if *reverse {
+        span.reverse();
+    }
+    span.into_iter().skip(index - before).take(limit).collect()
+}
+
+// Ids created within `rank_window_seconds` of the newest post, oldest first. Like danbooru only
+// these are ranked, so ranking costs the same however many posts a query matches. The window
+// ends at the newest post rather than now, a mirror that stopped syncing still ranks something.
+fn rank_window<'d>(db: &'d Db, config: &Config) -> &'d [ID] {
+    let created_at_index: &CreatedAtIndex = db.index().unwrap();
+    let range_index = &created_at_index.range_index;
+    let ids = range_index.ids();
+    let created_ats = range_index.id_values();
+    let created_at = |id: &ID| created_ats.get(id).copied().unwrap_or(0);
+    let Some(newest) = ids.last().map(created_at) else {
+        return ids;
+    };
+    let start = newest.saturating_sub(config.rank_window_seconds.saturating_mul(1000));
+    &ids[ids.partition_point(|id| created_at(id) < start)..]
+}
+
+// Danbooru's hot ranking, log of the score plus a term growing with the creation time,
+// so a new post needs a much smaller score to outrank an old one.
+// `ids` should come from `rank_window`.
+fn rank(db: &Db, config: &Config, ids: Vec<ID>) -> Vec<ID> {
+    let score_index: &ScoreIndex = db.index().unwrap();
+    let created_at_index: &CreatedAtIndex = db.index().unwrap();
+    let scores = score_index.range_index.id_values();
+    let created_ats = created_at_index.range_index.id_values();
+    let id_index: &IdIndex = db.index().unwrap();
+    let mut ranked: Vec<_> = ids
+        .into_iter()
+        .map(|id| {
+            let score = scores.get(&id).copied().unwrap_or(0).max(1) as f64;
+            let created_at = created_ats.get(&id).copied().unwrap_or(0) as f64 / 1000.0;
+            let rank = score.log(3.0) + created_at / config.rank_decay_seconds;
+            (rank, id_index.id_to_post_id(id), id)
+        })
+        .collect();
+    ranked.sort_unstable_by(|(a, a_post_id, _), (b, b_post_id, _)| {
+        b.total_cmp(a).then(a_post_id.cmp(b_post_id))
+    });
+    ranked.into_iter().map(|(_, _, id)| id).collect()
+}
+
+// The same order for the same seed, keyed on post ids so it survives reloads. Only the first
+// `count` ids of it are kept, so each page is a disjoint slice without ordering every match.
+fn shuffle(db: &Db, ids: impl Iterator<Item = ID>, seed: u64, count: usize) -> Vec<ID> {
+    let id_index: &IdIndex = db.index().unwrap();
+    let mut heap = BinaryHeap::with_capacity(count.saturating_add(1).min(1 << 16));
+    for id in ids {
+        heap.push((fxhash::hash64(&(seed, id_index.id_to_post_id(id))), id));
+        if heap.len() > count {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect()
+}
+
+// For `Db::query` errors, the term an index rejected and why if there is one.
+fn malformed_query(text: &str) -> ApiError {
+    let message = take_query_error().unwrap_or_else(|| "malformed query".into());
+    ApiError::bad_query(text, message)
+}
+
 pub async fn get_posts(
     State(db): State<Arc<RwLock<Db>>>,
-    RQuery(GetPostsQuery {
+    State(query_cache): State<Arc<QueryCache>>,
+    State(score_floor): State<ScoreFloor>,
+    State(config): State<Config>,
+    headers: HeaderMap,
+    Params(query): Params<GetPostsQuery>,
+) -> Result<Response, ApiError> {
+    let csv = accepts_csv(&headers);
+    posts(&db, &query_cache, score_floor, &config, query, csv).await
+}
+
+// Same as `get_posts` for queries too long to fit in a url.
+pub async fn post_posts(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(query_cache): State<Arc<QueryCache>>,
+    State(score_floor): State<ScoreFloor>,
+    State(config): State<Config>,
+    headers: HeaderMap,
+    query: Result<Json<GetPostsQuery>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(query) = query.map_err(|rejection| ApiError::bad_request(rejection.body_text()))?;
+    let csv = accepts_csv(&headers);
+    posts(&db, &query_cache, score_floor, &config, query, csv).await
+}
+
+fn accepts_csv(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/csv"))
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn posts_csv<'p>(posts: impl Iterator<Item = &'p BooruPost>) -> String {
+    let mut csv = String::from("id,rating,score,width,height,file_ext,created_at,tags\n");
+    for post in posts {
+        let row = [
+            post.id.to_string(),
+            format!("{:?}", post.rating).to_lowercase(),
+            (post.up_score + post.down_score).to_string(),
+            post.width.to_string(),
+            post.height.to_string(),
+            post.file_ext.as_str().to_string(),
+            post.created_at.to_string(),
+            post.tags.join(" "),
+        ];
+        let row: Vec<_> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+async fn posts(
+    db: &RwLock<Db>,
+    query_cache: &QueryCache,
+    score_floor: ScoreFloor,
+    config: &Config,
+    GetPostsQuery {
         query,
         sort,
+        tags_all,
+        tags_any,
         page,
         limit,
-    }): RQuery<GetPostsQuery>,
-) -> Json<PostsResponse> {
+        before_id,
+        allow_full_scan,
+        ids: custom_ids,
+        seed,
+        debug,
+        explain,
+        tree,
+        suggest,
+        fields,
+        format,
+    }: GetPostsQuery,
+    csv: bool,
+) -> Result<Response, ApiError> {
+    if before_id.is_some() && page.is_some() {
+        return Err(ApiError::bad_request(
+            "page and before_id are mutually exclusive",
+        ));
+    }
+    if before_id.is_some() && !matches!(sort, Sort::IdDesc) {
+        return Err(ApiError::bad_request("before_id requires the id_desc sort"));
+    }
+
+    let custom_ids: Vec<u32> = match (&sort, custom_ids) {
+        (Sort::Custom, Some(ids)) => ids
+            .split(',')
+            .map(|id| id.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| ApiError::bad_request("ids must be comma separated post ids"))?,
+        (Sort::Custom, None) => {
+            return Err(ApiError::bad_request("sort=custom requires ids"));
+        }
+        (_, _) => Vec::new(),
+    };
+
+    let seed = match sort {
+        Sort::Random => Some(seed.unwrap_or_else(rand::random)),
+        _ => None,
+    };
+
     let mut timings = PostsResponseTimings::default();
 
-    let mut query = Query::parse(&query).unwrap(); // TODO
+    let text = query;
+    let malformed = || malformed_query(&text);
+    let unparsed = || ApiError::bad_query(&text, "malformed query");
+    let mut query = parse_posts_query(&text, score_floor).ok_or_else(unparsed)?;
     query.simplify();
+    if tree {
+        return Ok(Json(QueryTree::from(&query)).into_response());
+    }
 
     let db = db.read().await;
 
     let start_time = Instant::now();
-    let result = db.query(&query).unwrap(); // TODO
+    let key = query_cache.key(canonical(&query));
+    take_query_error();
+    // With `debug` the query is always evaluated, term by term, and the ids used like cached ones.
+    let mut cached = match debug {
+        true => None,
+        false => query_cache.get(&key),
+    };
+    let mut result = match (&cached, debug) {
+        (Some(_), _) => None,
+        (None, true) => {
+            let (ids, terms) = query_timed(&db, &query).ok_or_else(malformed)?;
+            timings.terms = Some(terms);
+            cached = Some(Arc::new(ids));
+            None
+        }
+        (None, false) => Some(db.query(&query).map_err(|_| malformed())?),
+    };
+    let mut matched = match (&cached, &result) {
+        (Some(ids), _) => ids.len(),
+        (None, Some(result)) => result.matched(),
+        (None, None) => unreachable!(),
+    };
+    match (&cached, &result) {
+        (_, Some(result)) if query_cache.accepts(matched) => {
+            query_cache.insert(key, Arc::new(result.get(0, matched, false)));
+        }
+        (Some(ids), None) if debug => query_cache.insert(key, ids.clone()),
+        _ => {}
+    }
+    // Tag lists narrow what `q` matched, the ids are then used like cached ones.
+    let tags_all = tags_all.unwrap_or_default();
+    let tags_any = tags_any.unwrap_or_default();
+    if let Some(tag_ids) = tag_lists_ids(&db, &tags_all, &tags_any) {
+        let mut ids = match (&cached, result.take()) {
+            (Some(ids), _) => ids.to_vec(),
+            (None, Some(result)) => result.get(0, matched, false),
+            (None, None) => unreachable!(),
+        };
+        ids.sort_unstable();
+        let ids = intersection(&ids, &tag_ids);
+        matched = ids.len();
+        cached = Some(Arc::new(ids));
+    }
     let elapsed = start_time.elapsed().as_nanos();
     timings.query = elapsed as u64;
+    let unmatched = (explain || suggest).then(|| unmatched_tags(&db, &text));
+    let suggestions = match (&unmatched, suggest) {
+        (Some(unmatched), true) => {
+            let tag_index: &TagIndex = db.index().unwrap();
+            let suggestions = unmatched
+                .iter()
+                .filter(|tag| !tag.contains('*'))
+                .map(|tag| (tag.clone(), tag_index.suggest(tag, SUGGESTIONS_PER_TAG)))
+                .collect();
+            Some(suggestions)
+        }
+        _ => None,
+    };
+    let unmatched_tags = unmatched.filter(|_| explain);
+
+    let limit = limit.min(config.posts_max_limit);
+    // Only counting, skip sorting and ignore `page`.
+    if limit == 0 {
+        drop(db);
+        let response = PostsResponse {
+            matched,
+            url: None,
+            page: 0,
+            total_pages: 0,
+            limit,
+            max_limit: config.posts_max_limit,
+            next_cursor: None,
+            seed,
+            posts: None,
+            unmatched_tags,
+            suggestions,
+            timings,
+        };
+        return Ok((Extension(Matched(matched)), Json(response)).into_response());
+    }
+    if !allow_full_scan {
+        let id_index: &IdIndex = db.index().unwrap();
+        let total = id_index.range_index.ids().len();
+        let offset = page.unwrap_or(0).saturating_mul(limit);
+        let most = matched as f64 > total as f64 * config.full_scan_fraction;
+        if offset > config.full_scan_max_offset && most {
+            let message = "query matches most posts, \
+                narrow it or pass allow_full_scan=true to page this deep";
+            let detail = json!({ "matched": matched, "max_offset": config.full_scan_max_offset });
+            return Err(
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "full_scan", message)
+                    .with_detail(detail),
+            );
+        }
+    }
 
-    let index = page * limit;
     let start_time = Instant::now();
-    let ids = match sort {
-        Sort::IdAsc | Sort::IdDesc => {
-            let reverse = matches!(sort, Sort::IdDesc);
+    let id_index: &IdIndex = db.index().unwrap();
+    let window: &[ID] = match sort {
+        Sort::Rank => rank_window(&db, config),
+        _ => &[],
+    };
+    let windowed = window.iter().copied();
+    // Every post up to the end of the requested page.
+    let shown = page.unwrap_or(0).saturating_add(1).saturating_mul(limit);
+    let custom: Vec<ID> = match (&sort, &cached, &result) {
+        (Sort::Rank, Some(ids), _) => {
+            let ids = cache::get_sorted(ids, windowed, 0, window.len(), false);
+            rank(&db, config, ids)
+        }
+        (Sort::Rank, None, Some(result)) => {
+            let ids = result.get_sorted(windowed, 0, window.len(), false);
+            rank(&db, config, ids)
+        }
+        (Sort::Random, Some(ids), _) => shuffle(&db, ids.iter().copied(), seed.unwrap(), shown),
+        (Sort::Random, None, Some(result)) => {
+            let ids = result.get(0, matched, false).into_iter();
+            shuffle(&db, ids, seed.unwrap(), shown)
+        }
+        _ => custom_ids
+            .into_iter()
+            .filter_map(|post_id| id_index.post_id_to_id(post_id))
+            .collect(),
+    };
+    // Only posts in the rank window are ranked and can be paged through.
+    let matched = match sort {
+        Sort::Rank => custom.len(),
+        _ => matched,
+    };
+    let order = match before_id {
+        Some(before_id) => {
+            // Walk down from the cursor, the cursor post itself doesn't need to exist anymore.
             let id_index: &IdIndex = db.index().unwrap();
-            let sort = id_index.range_index.ids().iter().copied();
-            result.get_sorted(sort, index, limit, reverse)
+            let ids = id_index.range_index.ids();
+            let end = ids.partition_point(|&id| id_index.id_to_post_id(id).unwrap() < before_id);
+            (&ids[..end], true, None)
         }
-        Sort::ScoreAsc | Sort::ScoreDesc => {
-            let reverse = matches!(sort, Sort::ScoreDesc);
-            let score_index: &ScoreIndex = db.index().unwrap();
-            let sort = score_index.range_index.ids().iter().copied();
-            result.get_sorted(sort, index, limit, reverse)
+        None => sort_order(&db, &sort, &custom),
+    };
+    let index = page
+        .unwrap_or(0)
+        .checked_mul(limit)
+        .filter(|&index| index < matched);
+    let ids = match (index, &cached, &result) {
+        (None, _, _) => Vec::new(),
+        (Some(index), Some(ids), _) => {
+            let get_sorted = |order: &[ID], index, limit, reverse| {
+                cache::get_sorted(ids, order.iter().copied(), index, limit, reverse)
+            };
+            sorted_page(id_index, &order, get_sorted, index, limit)
+        }
+        (Some(index), None, Some(result)) => {
+            let get_sorted = |order: &[ID], index, limit, reverse| {
+                result.get_sorted(order.iter().copied(), index, limit, reverse)
+            };
+            sorted_page(id_index, &order, get_sorted, index, limit)
         }
+        (Some(_), None, None) => unreachable!(),
     };
     let elapsed = start_time.elapsed().as_nanos();
     timings.sort = elapsed as u64;
 
+    if csv {
+        let post_index: &PostIndex = db.index().unwrap();
+        let body = posts_csv(ids.into_iter().filter_map(|id| post_index.get(id)));
+        drop(db);
+        let content_type = [(header::CONTENT_TYPE, "text/csv")];
+        return Ok((content_type, Extension(Matched(matched)), body).into_response());
+    }
+    if let Format::Danbooru = format {
+        let post_index: &PostIndex = db.index().unwrap();
+        let tag_index: &TagIndex = db.index().unwrap();
+        let posts: Vec<_> = ids
+            .into_iter()
+            .filter_map(|id| post_index.get(id))
+            .map(|post| post.to_raw_json(|name| tag_index.category(name)))
+            .collect();
+        drop(db);
+        return Ok((Extension(Matched(matched)), Json(posts)).into_response());
+    }
+
+    let posts = match fields {
+        Fields::Ids => None,
+        Fields::Full => {
+            let post_index: &PostIndex = db.index().unwrap();
+            let posts = ids.iter().filter_map(|&id| post_index.get(id));
+            Some(posts.map(PostDto::from).collect())
+        }
+    };
     let id_index: &IdIndex = db.index().unwrap();
     let post_ids: Vec<_> = ids
         .into_iter()
-        .map(|id| id_index.id_to_post_id(id).unwrap().to_string())
+        .map(|id| id_index.id_to_post_id(id).unwrap())
         .collect();
     drop(db);
 
-    let id_search = post_ids.join(",");
+    let next_cursor = match sort {
+        Sort::IdDesc if post_ids.len() == limit => post_ids.last().copied(),
+        _ => None,
+    };
+    let id_search = post_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
     let url = format!("https://danbooru.donmai.us/posts?tags=id:{id_search}+order:custom");
 
-    let matched = result.matched();
     let response = PostsResponse {
         matched,
-        url,
+        url: Some(url),
+        page: page.unwrap_or(0),
+        total_pages: total_pages(matched, limit),
+        limit,
+        max_limit: config.posts_max_limit,
+        next_cursor,
+        seed,
+        posts,
+        unmatched_tags,
+        suggestions,
         timings,
     };
-    response.into()
+    Ok((Extension(Matched(matched)), Json(response)).into_response())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetRandomPostsQuery {
+    #[serde(default, alias = "q")]
+    query: String,
+    #[serde(default = "random_posts_default_count")]
+    count: usize,
+}
+
+const fn random_posts_default_count() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+pub struct RandomPostsResponse {
+    posts: Vec<u32>,
+    matched: usize,
+}
+
+pub async fn get_random_posts(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(query_cache): State<Arc<QueryCache>>,
+    State(score_floor): State<ScoreFloor>,
+    State(config): State<Config>,
+    Params(GetRandomPostsQuery { query, count }): Params<GetRandomPostsQuery>,
+) -> Result<Json<RandomPostsResponse>, ApiError> {
+    let text = query;
+    let malformed = || malformed_query(&text);
+    let unparsed = || ApiError::bad_query(&text, "malformed query");
+    let mut query = parse_posts_query(&text, score_floor).ok_or_else(unparsed)?;
+    query.simplify();
+
+    let db = db.read().await;
+    let key = query_cache.key(canonical(&query));
+    let ids = match query_cache.get(&key) {
+        Some(ids) => ids,
+        None => {
+            take_query_error();
+            let result = db.query(&query).map_err(|_| malformed())?;
+            let ids = Arc::new(result.get(0, result.matched(), false));
+            query_cache.insert(key, ids.clone());
+            ids
+        }
+    };
+
+    // Picks random positions instead of shuffling or sorting the whole result.
+    let matched = ids.len();
+    let count = count.min(config.posts_max_limit).min(matched);
+    let id_index: &IdIndex = db.index().unwrap();
+    let posts = rand::seq::index::sample(&mut rand::thread_rng(), matched, count)
+        .into_iter()
+        .map(|index| id_index.id_to_post_id(ids[index]).unwrap())
+        .collect();
+    drop(db);
+
+    Ok(RandomPostsResponse { posts, matched }.into())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetSimilarPostsQuery {
+    id: u32,
+    #[serde(
+        default = "posts_default_limit",
+        deserialize_with = "deserialize_limit"
+    )]
+    limit: usize,
+}
+
+// Tags on more posts than this are skipped, they say little about similarity and are slow to count.
+const SIMILAR_MAX_TAG_POSTS: usize = 100_000;
+
+#[derive(Serialize)]
+pub struct SimilarPostsResponse {
+    // Post ids and their tag overlap (jaccard index), most similar first.
+    posts: Vec<(u32, f32)>,
+}
+
+pub async fn get_similar_posts(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(config): State<Config>,
+    Params(GetSimilarPostsQuery { id, limit }): Params<GetSimilarPostsQuery>,
+) -> Result<Json<SimilarPostsResponse>, ApiError> {
+    let db = db.read().await;
+    let id_index: &IdIndex = db.index().unwrap();
+    let post_index: &PostIndex = db.index().unwrap();
+    let tag_index: &TagIndex = db.index().unwrap();
+    let post = id_index
+        .post_id_to_id(id)
+        .and_then(|id| post_index.get(id))
+        .ok_or_else(|| ApiError::not_found("post not found"))?;
+
+    let mut shared: FxHashMap<ID, u32> = FxHashMap::default();
+    for tag in &post.tags {
+        let Some(posts) = tag_index.keys_index.items.get(tag) else {
+            continue;
+        };
+        if posts.matched() > SIMILAR_MAX_TAG_POSTS {
+            continue;
+        }
+        for id in queryable_ids(posts) {
+            *shared.entry(id).or_default() += 1;
+        }
+    }
+
+    let mut similar: Vec<_> = shared
+        .into_iter()
+        .filter_map(|(id, shared)| {
+            let other = post_index.get(id)?;
+            if other.id == post.id {
+                return None;
+            }
+            let union = post.tags.len() + other.tags.len() - shared as usize;
+            Some((other.id, shared as f32 / union as f32))
+        })
+        .collect();
+    drop(db);
+    similar.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+    similar.truncate(limit.min(config.posts_max_limit));
+
+    Ok(SimilarPostsResponse { posts: similar }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{post::tests::post, tests::db};
+
+    fn post_ids(db: &Db, ids: &[ID]) -> Vec<u32> {
+        let id_index: &IdIndex = db.index().unwrap();
+        ids.iter()
+            .map(|&id| id_index.id_to_post_id(id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rank_only_ranks_the_window() {
+        let aged = |id: i32, hours: i64, up_score: i32| {
+            let post = post(id);
+            BooruPost {
+                created_at: post.created_at - Duration::hours(hours),
+                up_score,
+                ..post
+            }
+        };
+        let db = db(vec![
+            aged(1, 24 * 10, 1_000),
+            aged(2, 1, 5),
+            aged(3, 0, 1),
+            aged(4, 47, 5),
+            aged(5, 1, 5),
+        ]);
+        let config = Config::default();
+        let window = rank_window(&db, &config);
+        let mut windowed = post_ids(&db, window);
+        windowed.sort_unstable();
+        assert_eq!(windowed, [2, 3, 4, 5]);
+
+        // Equal ranks keep their id order.
+        let ranked = rank(&db, &config, window.to_vec());
+        assert_eq!(post_ids(&db, &ranked), [2, 5, 3, 4]);
+
+        let config = Config {
+            rank_window_seconds: 2 * 60 * 60,
+            ..Config::default()
+        };
+        let mut windowed = post_ids(&db, rank_window(&db, &config));
+        windowed.sort_unstable();
+        assert_eq!(windowed, [2, 3, 5]);
+    }
+
+    #[test]
+    fn tied_pages_dont_overlap() {
+        let mut db = db([6, 3, 1, 5, 2, 4].into_iter().map(post).collect());
+        let page = |db: &Db, page: usize| {
+            let id_index: &IdIndex = db.index().unwrap();
+            let all = id_index.range_index.ids();
+            let get_sorted = |order: &[ID], index, limit, reverse| {
+                cache::get_sorted(all, order.iter().copied(), index, limit, reverse)
+            };
+            let order = sort_order(db, &Sort::ScoreDesc, &[]);
+            post_ids(db, &sorted_page(id_index, &order, get_sorted, page * 3, 3))
+        };
+        let first = page(&db, 0);
+        let second = page(&db, 1);
+        let mut all = [first.clone(), second.clone()].concat();
+        all.sort_unstable();
+        assert_eq!(all, [1, 2, 3, 4, 5, 6]);
+
+        // Moving a post away and back puts it in the same place among its ties.
+        let id_index: &IdIndex = db.index().unwrap();
+        let id = id_index.post_id_to_id(2).unwrap();
+        let scored = BooruPost {
+            up_score: 1,
+            ..post(2)
+        };
+        db.update(id, &post(2), &scored);
+        assert_eq!(page(&db, 0)[0], 2);
+        db.update(id, &scored, &post(2));
+        assert_eq!(page(&db, 0), first);
+        assert_eq!(page(&db, 1), second);
+    }
+
+    #[test]
+    fn random_pages_are_disjoint_and_stable() {
+        let db = db((1..=10).map(post).collect());
+        let id_index: &IdIndex = db.index().unwrap();
+        let ids = id_index.range_index.ids();
+        let page = |seed: u64, page: usize| {
+            let shuffled = shuffle(&db, ids.iter().copied(), seed, (page + 1) * 3);
+            post_ids(&db, &shuffled[page * 3..])
+        };
+        let first = page(7, 0);
+        let second = page(7, 1);
+        assert_eq!(first.len(), 3);
+        assert_eq!(second.len(), 3);
+        assert!(first.iter().all(|post_id| !second.contains(post_id)));
+        assert_eq!(page(7, 0), first);
+        assert_eq!(page(7, 1), second);
+
+        // Both are slices of the same seed's order over every post.
+        let all = post_ids(&db, &shuffle(&db, ids.iter().copied(), 7, usize::MAX));
+        assert_eq!(all.len(), 10);
+        assert_eq!(all[..6], [first, second].concat());
+        // The last page is short.
+        assert_eq!(page(7, 3).len(), 1);
+    }
+
+    #[test]
+    fn reused_ids_sort_by_post_id() {
+        let mut db = db([1, 2, 3, 4].into_iter().map(post).collect());
+        let page = |db: &Db| {
+            let id_index: &IdIndex = db.index().unwrap();
+            let all = id_index.range_index.ids();
+            let get_sorted = |order: &[ID], index, limit, reverse| {
+                cache::get_sorted(all, order.iter().copied(), index, limit, reverse)
+            };
+            let order = sort_order(db, &Sort::ScoreAsc, &[]);
+            post_ids(db, &sorted_page(id_index, &order, get_sorted, 0, 10))
+        };
+        assert_eq!(page(&db), [1, 2, 3, 4]);
+
+        // Post 5 takes over the id post 1 had, ahead of every other id.
+        let id_index: &IdIndex = db.index().unwrap();
+        let id = id_index.post_id_to_id(1).unwrap();
+        db.remove(id, &post(1));
+        let id_index: &mut IdIndex = db.index_mut().unwrap();
+        assert_eq!(id_index.take_free_id(), Some(id));
+        db.insert(id, &post(5));
+        assert_eq!(page(&db), [2, 3, 4, 5]);
+    }
+
+    // Matched count of `GET /posts` with `params`.
+    async fn matched(db: &RwLock<Db>, params: serde_json::Value) -> Result<usize, ApiError> {
+        let params = serde_json::from_value(params).unwrap();
+        let query_cache = QueryCache::new(0, 0);
+        let config = Config::default();
+        let response = posts(db, &query_cache, ScoreFloor(None), &config, params, false).await?;
+        Ok(response.extensions().get::<Matched>().unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn empty_query_matches_everything() {
+        let db = RwLock::new(db(vec![post(1), post(2), post(3)]));
+        assert_eq!(matched(&db, json!({})).await.unwrap(), 3);
+        assert_eq!(matched(&db, json!({"q": ""})).await.unwrap(), 3);
+        assert_eq!(matched(&db, json!({"q": "   "})).await.unwrap(), 3);
+        assert_eq!(
+            matched(&db, json!({"q": " \t\n", "limit": 0}))
+                .await
+                .unwrap(),
+            3
+        );
+
+        let error = matched(&db, json!({"q": "("})).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.error_code, "bad_query");
+    }
+
+    #[tokio::test]
+    async fn tag_lists_take_names_literally() {
+        let tagged = |id: i32, tags: &[&str]| BooruPost {
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+            ..post(id)
+        };
+        let db = RwLock::new(db(vec![
+            tagged(1, &["ratio:16:9", "a"]),
+            tagged(2, &["*", "a"]),
+            tagged(3, &["a", "b"]),
+            tagged(4, &["b", "-_-"]),
+        ]));
+        let db = &db;
+        let count = |params| async move { matched(db, params).await.unwrap() };
+        assert_eq!(count(json!({"tags_all": "a"})).await, 3);
+        assert_eq!(
+            count(json!({"tags_all": "a", "tags_any": "ratio:16:9,*"})).await,
+            2
+        );
+        assert_eq!(count(json!({"tags_any": "-_-,ratio:16:9"})).await, 2);
+        assert_eq!(count(json!({"tags_all": "a,b"})).await, 1);
+        assert_eq!(count(json!({"q": "b", "tags_all": "a"})).await, 1);
+        assert_eq!(count(json!({"tags_all": "a,missing"})).await, 0);
+    }
+
+    #[tokio::test]
+    async fn similar_posts_read_tags_literally() {
+        let tagged = |id: i32, tags: &[&str]| BooruPost {
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+            ..post(id)
+        };
+        let tags = ["ratio:16:9", "-_-", "~tilde", "/slash", "plain"];
+        let db = Arc::new(RwLock::new(db(vec![
+            tagged(1, &tags),
+            tagged(2, &tags[..4]),
+            tagged(3, &["plain"]),
+            tagged(4, &["other"]),
+        ])));
+        let params = GetSimilarPostsQuery { id: 1, limit: 10 };
+        let response = get_similar_posts(State(db), State(Config::default()), Params(params))
+            .await
+            .unwrap();
+        assert_eq!(response.0.posts, [(2, 0.8), (3, 0.2)]);
+    }
 }
@@ -0,0 +1,568 @@
+use std::time::Instant;
+
+use booru_db::{query::Item, Query, ID};
+use serde::Serialize;
+
+use crate::{
+    index::{queryable_ids, FileCategory, TagIndex},
+    Db,
+};
+
+// Top level whitespace separated terms, anything inside parentheses or double quotes stays
+// in one term.
+fn terms(text: &str) -> Vec<&str> {
+    let mut terms = Vec::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            c if c.is_whitespace() && depth == 0 => {
+                if let Some(start) = start.take() {
+                    terms.push(&text[start..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        terms.push(&text[start..]);
+    }
+    terms
+}
+
+// `res:1920x1080` is `width:1920 height:1080`, a leading `<`/`>`/`=` operator applies to both sides
+// and either side can be left empty (`res:1920x`) or be a range (`res:1920..x..1080`).
+// Returns None for other terms and Some(None) for a malformed `res:`.
+fn resolution(term: &str) -> Option<Option<Query<String>>> {
+    let (inverse, term) = match term.strip_prefix('-') {
+        Some(term) => (true, term),
+        None => (false, term),
+    };
+    let value = term.strip_prefix("res:")?;
+    let op_len = value
+        .find(|c| !['<', '>', '='].contains(&c))
+        .unwrap_or(value.len());
+    let (op, dims) = value.split_at(op_len);
+    let Some((width, height)) = dims.split_once(['x', 'X']) else {
+        return Some(None);
+    };
+    let mut parts = Vec::new();
+    for (ident, value) in [("width", width), ("height", height)] {
+        if value.is_empty() {
+            continue;
+        }
+        let Ok(query) = Query::parse(&format!("{ident}:{op}{value}")) else {
+            return Some(None);
+        };
+        parts.push(query);
+    }
+    if parts.is_empty() {
+        return Some(None);
+    }
+    Some(Some(Query::new(Item::AndChain(parts), inverse)))
+}
+
+// `source:"https://example.com/a b"` is passed to the index as is without the quotes,
+// `Query::parse` would split it on the space. Returns None for terms without quotes.
+fn quoted(term: &str) -> Option<Query<String>> {
+    if !term.contains('"') {
+        return None;
+    }
+    let (inverse, term) = match term.strip_prefix('-') {
+        Some(term) => (true, term),
+        None => (false, term),
+    };
+    Some(Query::new(Item::Single(term.replace('"', "")), inverse))
+}
+
+// `width:>height` and `height:>width` are spelled `orientation:landscape`/`portrait` in the index.
+fn orientations(text: &str) -> String {
+    let terms: Vec<_> = terms(text)
+        .into_iter()
+        .map(|term| {
+            let prefix_len = term.len() - term.trim_start_matches(['-', '~']).len();
+            let (prefix, term) = term.split_at(prefix_len);
+            let orientation = match term {
+                "width:>height" | "height:<width" => "orientation:landscape",
+                "height:>width" | "width:<height" => "orientation:portrait",
+                "width:height" | "height:width" => "orientation:square",
+                _ => return format!("{prefix}{term}"),
+            };
+            format!("{prefix}{orientation}")
+        })
+        .collect();
+    terms.join(" ")
+}
+
+fn parse_term(term: &str) -> Option<Query<String>> {
+    if let Some(query) = quoted(term) {
+        return Some(query);
+    }
+    match resolution(term) {
+        Some(query) => query,
+        None => Query::parse(term).ok(),
+    }
+}
+
+// Sorted ids of posts with every tag in `tags_all` and any in `tags_any`, both comma separated.
+// Read straight from the tag index so names are taken as is, `ratio:16:9` or `*` are only tags.
+// None if both lists are empty.
+pub fn tag_lists_ids(db: &Db, tags_all: &str, tags_any: &str) -> Option<Vec<ID>> {
+    let tag_index: &TagIndex = db.index().unwrap();
+    let tags = |list: &str| -> Vec<_> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| {
+                let queryable = tag_index.keys_index.items.get(tag);
+                let mut ids = queryable.map(queryable_ids).unwrap_or_default();
+                ids.sort_unstable();
+                ids
+            })
+            .collect()
+    };
+    let mut lists = tags(tags_all);
+    let any = tags(tags_any);
+    if !any.is_empty() {
+        let mut ids: Vec<_> = any.into_iter().flatten().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        lists.push(ids);
+    }
+    let mut lists = lists.into_iter();
+    let first = lists.next()?;
+    Some(lists.fold(first, |ids, other| intersection(&ids, &other)))
+}
+
+// Other spellings of registered idents, `file_size:` is how danbooru names the column.
+const IDENT_ALIASES: &[(&str, &str)] = &[("file_size", "filesize")];
+
+// The registered ident `ident` is spelled as.
+fn canonical_ident(ident: &str) -> &str {
+    IDENT_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == ident)
+        .map_or(ident, |(_, name)| name)
+}
+
+// `term` with its ident renamed if aliased. `is:<category>` becomes `file_ext:<category>`,
+// `file_ext` already groups extensions by category.
+fn canonical_term(term: String) -> String {
+    let Some((ident, value)) = term.split_once(':') else {
+        return term;
+    };
+    if ident == "is" && value.parse::<FileCategory>().is_ok() {
+        return format!("file_ext:{value}");
+    }
+    // Only opts out of the default status filter, see `parse_posts_query`.
+    if ident == "status" && value.eq_ignore_ascii_case("any") {
+        return "id:any".to_string();
+    }
+    match canonical_ident(ident) {
+        canonical if canonical != ident => format!("{canonical}:{value}"),
+        _ => term,
+    }
+}
+
+// Canonical terms in place of every term of `query`, including ones nested in parentheses.
+fn canonical_idents(query: Query<String>) -> Query<String> {
+    let item = match query.item {
+        Item::Single(term) => Item::Single(canonical_term(term)),
+        Item::AndChain(queries) => {
+            Item::AndChain(queries.into_iter().map(canonical_idents).collect())
+        }
+        Item::OrChain(queries) => {
+            Item::OrChain(queries.into_iter().map(canonical_idents).collect())
+        }
+    };
+    Query::new(item, query.inverse)
+}
+
+// Danbooru style `~a ~b c -d` means `(a OR b) AND c AND NOT d`.
+// The `~` terms are parsed individually and ORed, terms this crate expands itself (`res:`) and
+// quoted ones are built separately, and everything is ANDed with the rest of the query.
+// An empty query matches every post.
+pub fn parse_query(text: &str) -> Option<Query<String>> {
+    parse_terms(text).map(canonical_idents)
+}
+
+fn parse_terms(text: &str) -> Option<Query<String>> {
+    if text.trim().is_empty() {
+        return Query::parse("id:any").ok();
+    }
+    let text = &orientations(text);
+    let mut and_terms = Vec::new();
+    let mut or_chain = Vec::new();
+    let mut parts = Vec::new();
+    for term in terms(text) {
+        if let Some(term) = term.strip_prefix('~') {
+            or_chain.push(parse_term(term)?);
+        } else if let Some(query) = resolution(term) {
+            parts.push(query?);
+        } else if let Some(query) = quoted(term) {
+            parts.push(query);
+        } else {
+            and_terms.push(term);
+        }
+    }
+    if or_chain.is_empty() && parts.is_empty() {
+        return Query::parse(text).ok();
+    }
+
+    if !and_terms.is_empty() {
+        parts.insert(0, Query::parse(&and_terms.join(" ")).ok()?);
+    }
+    if !or_chain.is_empty() {
+        parts.push(Query::new(Item::OrChain(or_chain), false));
+    }
+    match parts.len() {
+        1 => parts.pop(),
+        _ => Some(Query::new(Item::AndChain(parts), false)),
+    }
+}
+
+// Lowest score shown to queries not filtering on `score:` or `favcount:` themselves,
+// from `--score-floor`/`BOORU_DB_SCORE_FLOOR`. None shows every score.
+#[derive(Clone, Copy, Default)]
+pub struct ScoreFloor(pub Option<i32>);
+
+// Whether a term of `query` uses `ident`, including ones nested in parentheses.
+fn mentions(query: &Query<String>, ident: &str) -> bool {
+    match &query.item {
+        Item::Single(term) => term
+            .split_once(':')
+            .is_some_and(|(term_ident, _)| canonical_ident(term_ident) == ident),
+        Item::AndChain(queries) | Item::OrChain(queries) => {
+            queries.iter().any(|query| mentions(query, ident))
+        }
+    }
+}
+
+// Like danbooru deleted and banned posts are hidden unless the query mentions `status:` itself,
+// `status:any` opts out of the filter without filtering on status.
+// With a `ScoreFloor`, posts scored below it are hidden the same way.
+pub fn parse_posts_query(text: &str, score_floor: ScoreFloor) -> Option<Query<String>> {
+    let query = parse_terms(text)?;
+    let term = |term: String, inverse| Query::new(Item::Single(term), inverse);
+    let mut defaults = Vec::new();
+    if !mentions(&query, "status") {
+        defaults.push(term("status:deleted".to_string(), true));
+        defaults.push(term("status:banned".to_string(), true));
+    }
+    if let Some(floor) = score_floor.0 {
+        if !mentions(&query, "score") && !mentions(&query, "favcount") {
+            defaults.push(term(format!("score:>={floor}"), false));
+        }
+    }
+
+    let mut parts = defaults;
+    // An empty query matches every post, the defaults alone say as much.
+    if !text.trim().is_empty() || parts.is_empty() {
+        parts.insert(0, canonical_idents(query));
+    }
+    match parts.len() {
+        1 => parts.pop(),
+        _ => Some(Query::new(Item::AndChain(parts), false)),
+    }
+}
+
+// A parsed query as JSON, to see how a search was understood without running it.
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum QueryTree {
+    Term {
+        term: String,
+        inverse: bool,
+    },
+    And {
+        items: Vec<QueryTree>,
+        inverse: bool,
+    },
+    Or {
+        items: Vec<QueryTree>,
+        inverse: bool,
+    },
+}
+
+impl From<&Query<String>> for QueryTree {
+    fn from(query: &Query<String>) -> Self {
+        let inverse = query.inverse;
+        match &query.item {
+            Item::Single(term) => Self::Term {
+                term: term.clone(),
+                inverse,
+            },
+            Item::AndChain(queries) => Self::And {
+                items: queries.iter().map(Self::from).collect(),
+                inverse,
+            },
+            Item::OrChain(queries) => Self::Or {
+                items: queries.iter().map(Self::from).collect(),
+                inverse,
+            },
+        }
+    }
+}
+
+// The parsed and simplified query as text, so searches spelled differently but parsing the same
+// share a cache entry.
+pub fn canonical(query: &Query<String>) -> String {
+    serde_json::to_string(&QueryTree::from(query)).unwrap()
+}
+
+// `query` as search text again, for labelling its terms.
+fn query_text(query: &Query<String>) -> String {
+    let text = match &query.item {
+        Item::Single(term) => term.clone(),
+        Item::AndChain(queries) => {
+            let terms: Vec<_> = queries.iter().map(query_text).collect();
+            format!("({})", terms.join(" "))
+        }
+        Item::OrChain(queries) => {
+            let terms: Vec<_> = queries
+                .iter()
+                .map(|query| format!("~{}", query_text(query)))
+                .collect();
+            format!("({})", terms.join(" "))
+        }
+    };
+    match query.inverse {
+        true => format!("-{text}"),
+        false => text,
+    }
+}
+
+// Evaluates `query` one top level term at a time and intersects the results, timing each term
+// in nanoseconds. Only meant for debugging slow queries, the ids are those `Db::query` would
+// match in ascending order and are used in its place, so the timings are of the real work.
+pub fn query_timed(db: &Db, query: &Query<String>) -> Option<(Vec<ID>, Vec<(String, u64)>)> {
+    let terms = match &query.item {
+        Item::AndChain(queries) if !query.inverse => queries.iter().collect(),
+        _ => vec![query],
+    };
+    let mut ids: Option<Vec<ID>> = None;
+    let mut timings = Vec::with_capacity(terms.len());
+    for term in terms {
+        let start_time = Instant::now();
+        let result = db.query(term).ok()?;
+        let mut term_ids = result.get(0, result.matched(), false);
+        term_ids.sort_unstable();
+        ids = Some(match ids {
+            None => term_ids,
+            Some(ids) => intersection(&ids, &term_ids),
+        });
+        let elapsed = start_time.elapsed().as_nanos();
+        timings.push((query_text(term), elapsed as u64));
+    }
+    Some((ids.unwrap_or_default(), timings))
+}
+
+// Ids in both ascending `a` and `b`.
+pub fn intersection(a: &[ID], b: &[ID]) -> Vec<ID> {
+    let mut ids = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                ids.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    ids
+}
+
+// Plain tag terms (no `ident:`, wildcards included) that match no posts on their own,
+// with any `-`/`~` prefix dropped. Helps spot the misspelled tag in a query matching nothing.
+pub fn unmatched_tags(db: &Db, text: &str) -> Vec<String> {
+    terms(text)
+        .into_iter()
+        .map(|term| term.trim_start_matches(['-', '~']))
+        .filter(|term| !term.is_empty() && !term.contains(':') && !term.starts_with('('))
+        .filter(|term| {
+            parse_term(term)
+                .and_then(|query| db.query(&query).ok())
+                .map_or(true, |result| result.matched() == 0)
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        index::take_query_error,
+        post::{tests::post, Status},
+        tests::{db, matches, search},
+        BooruPost,
+    };
+
+    // Like `search`, with the `/posts` defaults and no score floor.
+    fn posts_search(db: &crate::Db, text: &str) -> Vec<u32> {
+        matches(db, &parse_posts_query(text, ScoreFloor(None)).unwrap())
+    }
+
+    fn tagged(id: i32, tags: &[&str]) -> BooruPost {
+        BooruPost {
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+            ..post(id)
+        }
+    }
+
+    fn tree(text: &str) -> serde_json::Value {
+        serde_json::to_value(QueryTree::from(&parse_query(text).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn canonical_ignores_spelling() {
+        let canonical = |text: &str| canonical(&parse_query(text).unwrap());
+        assert_eq!(canonical("a   b"), canonical(" a b "));
+        assert_eq!(canonical("file_size:5kb"), canonical("filesize:5kb"));
+        assert_ne!(canonical("a b"), canonical("a -b"));
+        assert_ne!(canonical("a b"), canonical("~a ~b"));
+    }
+
+    #[test]
+    fn is_category_is_file_ext() {
+        assert_eq!(tree("is:animated"), tree("file_ext:animated"));
+        assert_eq!(tree("-is:video"), tree("-file_ext:video"));
+        assert_eq!(tree("is:note_locked")["term"], "is:note_locked");
+    }
+
+    #[test]
+    fn file_size_is_filesize() {
+        assert_eq!(tree("file_size:>1mb"), tree("filesize:>1mb"));
+        assert_eq!(tree("a (b -file_size:5kb)"), tree("a (b -filesize:5kb)"));
+    }
+
+    #[test]
+    fn indices_report_why_a_term_is_rejected() {
+        let db = db(vec![post(1)]);
+        let error = |text: &str| {
+            take_query_error();
+            assert!(db.query(&parse_query(text).unwrap()).is_err());
+            take_query_error().unwrap()
+        };
+        assert_eq!(error("file_size:5tb"), "filesize:5tb: unknown unit");
+        assert_eq!(error("a -filesize:5tb"), "-filesize:5tb: unknown unit");
+        assert_eq!(error("rating:x"), "rating:x: unknown keyword");
+        assert_eq!(error("score:1..2..3"), "score:1..2..3: malformed range");
+        assert_eq!(error("id:1,x"), "id:1,x: not a number");
+        assert_eq!(error("parent_id:5,y"), "parent_id:5,y: not a number");
+        assert_eq!(error("status:gone"), "status:gone: unknown keyword");
+        assert_eq!(error("file_ext:.exe"), "file_ext:.exe: unknown keyword");
+        assert!(db.query(&parse_query("score:>1").unwrap()).is_ok());
+        assert_eq!(take_query_error(), None);
+    }
+
+    #[test]
+    fn quoted_values_stay_one_term() {
+        assert_eq!(
+            terms(r#"a source:"https://example.com/a b" -b"#),
+            ["a", r#"source:"https://example.com/a b""#, "-b"]
+        );
+        assert_eq!(terms(r#"~source:"*x: y*" ("a b")"#).len(), 2);
+
+        let sourced = |id: i32, source: &str| BooruPost {
+            source: source.into(),
+            ..post(id)
+        };
+        let db = db(vec![
+            sourced(1, "https://example.com/a b"),
+            sourced(2, "https://other.org/x: y"),
+            sourced(3, "https://example.com:8080/c d"),
+        ]);
+        assert_eq!(search(&db, r#"source:"https://example.com/a b""#), [1, 3]);
+        assert_eq!(search(&db, r#"source:"*/a b*""#), [1]);
+        assert_eq!(search(&db, r#"source:"*:8080/c d""#), [3]);
+        assert_eq!(search(&db, r#"source:"*x: y""#), [2]);
+        assert_eq!(search(&db, r#"-source:"*a b*""#), [2, 3]);
+        assert_eq!(search(&db, r#"~source:"*a b*" ~source:"*x: y*""#), [1, 2]);
+        assert_eq!(search(&db, r#"source:"*example.com*" -source:"*c d""#), [1]);
+    }
+
+    #[test]
+    fn or_terms_group_before_and() {
+        let db = db(vec![
+            tagged(1, &["a", "c"]),
+            tagged(2, &["b", "c"]),
+            tagged(3, &["a", "c", "d"]),
+            tagged(4, &["c"]),
+            tagged(5, &["a", "b"]),
+        ]);
+        assert_eq!(search(&db, "~a ~b c -d"), [1, 2]);
+        assert_eq!(search(&db, "c ~a -d ~b"), [1, 2]);
+        assert_eq!(search(&db, "~a ~b"), [1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn score_floor_yields_to_any_score_term() {
+        let scored = |id: i32, up_score: i32| BooruPost {
+            up_score,
+            ..post(id)
+        };
+        let db = db(vec![scored(1, -5), scored(2, 0), scored(3, 5)]);
+        let search =
+            |text: &str| matches(&db, &parse_posts_query(text, ScoreFloor(Some(0))).unwrap());
+        assert_eq!(search(""), [2, 3]);
+        assert_eq!(search("score:<0"), [1]);
+        assert_eq!(search("(score:<0)"), [1]);
+        assert_eq!(search("~score:<0 ~id:3"), [1, 3]);
+        assert_eq!(search("favcount:0"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn deleted_posts_are_hidden_by_default() {
+        let with_status = |id: i32, status: Status| BooruPost { status, ..post(id) };
+        let db = db(vec![
+            with_status(1, Status::Active),
+            with_status(2, Status::Deleted),
+            with_status(3, Status::Banned),
+            with_status(4, Status::Pending),
+        ]);
+        assert_eq!(posts_search(&db, ""), [1, 4]);
+        assert_eq!(posts_search(&db, "status:deleted"), [2]);
+        assert_eq!(posts_search(&db, "-status:deleted"), [1, 3, 4]);
+        assert_eq!(posts_search(&db, "status:any"), [1, 2, 3, 4]);
+        assert_eq!(posts_search(&db, "status:ANY"), [1, 2, 3, 4]);
+        assert!(posts_search(&db, "-status:any").is_empty());
+        // Terms in parentheses and `~` terms count as mentions too.
+        assert_eq!(posts_search(&db, "(status:deleted)"), [2]);
+        assert_eq!(posts_search(&db, "~status:deleted ~status:banned"), [2, 3]);
+        assert_eq!(posts_search(&db, "(status:any)"), [1, 2, 3, 4]);
+        assert_eq!(posts_search(&db, "-status:pending"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn query_timed_matches_query() {
+        let db = db(vec![
+            tagged(1, &["a", "c"]),
+            tagged(2, &["b", "c"]),
+            tagged(3, &["a", "c", "d"]),
+            tagged(4, &["c"]),
+        ]);
+        for text in ["c -d ~a ~b", "-d", "a", "c -a -b", "(a d) ~b ~c"] {
+            let query = parse_posts_query(text, ScoreFloor(None)).unwrap();
+            let (ids, timings) = query_timed(&db, &query).unwrap();
+            let id_index: &crate::index::IdIndex = db.index().unwrap();
+            let mut post_ids: Vec<_> = ids
+                .into_iter()
+                .map(|id| id_index.id_to_post_id(id).unwrap())
+                .collect();
+            post_ids.sort_unstable();
+            assert_eq!(post_ids, posts_search(&db, text), "{text}");
+            assert!(!timings.is_empty());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use axum::{extract::State, Json};
+use booru_db::{index::RangeIndex, Query, ID};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    index::{
+        CreatedAtIndex, FavCountIndex, FileSizeIndex, HeightIndex, IdIndex, ScoreIndex, TagIndex,
+        WidthIndex,
+    },
+    post::{FileExt, Rating},
+    routes::{deserialize_buckets, ApiError, Params},
+    Db,
+};
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    posts: usize,
+    // Ids left by deleted posts, reused by the next inserts.
+    free_ids: usize,
+    tags: usize,
+    ratings: Vec<(Rating, usize)>,
+    file_exts: Vec<(FileExt, usize)>,
+    created_at: Option<(NaiveDateTime, NaiveDateTime)>,
+    sync_lag_ms: i64,
+}
+
+// Number of posts matching `ident:value`, the value is formatted with Debug which FromStr accepts.
+fn count(db: &Db, ident: &str, value: impl std::fmt::Debug) -> usize {
+    let query = Query::parse(&format!("{ident}:{value:?}")).unwrap();
+    db.query(&query).map_or(0, |result| result.matched())
+}
+
+pub async fn get_stats(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(sync_lag): State<Arc<AtomicI64>>,
+) -> Json<StatsResponse> {
+    let db = db.read().await;
+
+    let id_index: &IdIndex = db.index().unwrap();
+    let posts = id_index.range_index.ids().len();
+    let free_ids = id_index.free_ids();
+
+    let tag_index: &TagIndex = db.index().unwrap();
+    let tags = tag_index.keys_index.items.len();
+
+    let ratings = Rating::ALL
+        .into_iter()
+        .map(|rating| (rating, count(&db, "rating", rating)))
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    let file_exts = FileExt::ALL
+        .into_iter()
+        .map(|file_ext| {
+            let count = count(&db, "file_ext", &file_ext);
+            (file_ext, count)
+        })
+        .filter(|&(_, count)| count > 0)
+        .collect();
+
+    let created_at_index: &CreatedAtIndex = db.index().unwrap();
+    let range_index = &created_at_index.range_index;
+    let timestamp = |id: &ID| {
+        let millis = *range_index.id_values().get(id)?;
+        NaiveDateTime::from_timestamp_millis(millis)
+    };
+    let ids = range_index.ids();
+    let created_at = match (ids.first(), ids.last()) {
+        (Some(first), Some(last)) => timestamp(first).zip(timestamp(last)),
+        _ => None,
+    };
+    drop(db);
+
+    let response = StatsResponse {
+        posts,
+        free_ids,
+        tags,
+        ratings,
+        file_exts,
+        created_at,
+        sync_lag_ms: sync_lag.load(Ordering::Relaxed),
+    };
+    response.into()
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistogramField {
+    Score,
+    #[serde(alias = "filesize")]
+    FileSize,
+    #[serde(alias = "fav_count")]
+    Favcount,
+    Width,
+    Height,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HistogramQuery {
+    field: HistogramField,
+    #[serde(
+        default = "histogram_default_buckets",
+        deserialize_with = "deserialize_buckets"
+    )]
+    buckets: usize,
+}
+
+const fn histogram_default_buckets() -> usize {
+    20
+}
+
+const HISTOGRAM_MAX_BUCKETS: usize = 1000;
+
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    // Inclusive.
+    start: i64,
+    // Exclusive.
+    end: i64,
+    count: usize,
+}
+
+#[derive(Serialize)]
+pub struct HistogramResponse {
+    field: HistogramField,
+    buckets: Vec<HistogramBucket>,
+}
+
+// Every post's value in ascending order, `ids` is already sorted by value.
+fn sorted_values<T>(range_index: &RangeIndex<T>, value: impl Fn(&T) -> i64) -> Vec<i64> {
+    let id_values = range_index.id_values();
+    range_index
+        .ids()
+        .iter()
+        .filter_map(|id| id_values.get(id).map(&value))
+        .collect()
+}
+
+// Equal width buckets from the smallest to the largest value, fewer if the values span less.
+fn histogram(values: &[i64], buckets: usize) -> Vec<HistogramBucket> {
+    let (Some(&min), Some(&max)) = (values.first(), values.last()) else {
+        return Vec::new();
+    };
+    let span = max - min + 1;
+    let width = (span + buckets as i64 - 1) / buckets as i64;
+    let mut histogram: Vec<_> = (0..(span + width - 1) / width)
+        .map(|i| HistogramBucket {
+            start: min + i * width,
+            end: min + (i + 1) * width,
+            count: 0,
+        })
+        .collect();
+    for value in values {
+        histogram[((value - min) / width) as usize].count += 1;
+    }
+    histogram
+}
+
+pub async fn get_histogram(
+    State(db): State<Arc<RwLock<Db>>>,
+    Params(HistogramQuery { field, buckets }): Params<HistogramQuery>,
+) -> Result<Json<HistogramResponse>, ApiError> {
+    if !(1..=HISTOGRAM_MAX_BUCKETS).contains(&buckets) {
+        let message = format!("buckets must be between 1 and {HISTOGRAM_MAX_BUCKETS}");
+        return Err(ApiError::bad_request(message));
+    }
+
+    let db = db.read().await;
+    let values = match field {
+        HistogramField::Score => {
+            let index: &ScoreIndex = db.index().unwrap();
+            sorted_values(&index.range_index, |&score| score as i64)
+        }
+        HistogramField::FileSize => {
+            let index: &FileSizeIndex = db.index().unwrap();
+            sorted_values(&index.range_index, |file_size| file_size.0 as i64)
+        }
+        HistogramField::Favcount => {
+            let index: &FavCountIndex = db.index().unwrap();
+            sorted_values(&index.range_index, |&fav_count| fav_count as i64)
+        }
+        HistogramField::Width => {
+            let index: &WidthIndex = db.index().unwrap();
+            sorted_values(&index.range_index, |&width| width as i64)
+        }
+        HistogramField::Height => {
+            let index: &HeightIndex = db.index().unwrap();
+            sorted_values(&index.range_index, |&height| height as i64)
+        }
+    };
+    drop(db);
+
+    let response = HistogramResponse {
+        field,
+        buckets: histogram(&values, buckets),
+    };
+    Ok(response.into())
+}
@@ -1,15 +1,18 @@
 use std::{sync::Arc, time::Instant};
 
 use axum::{
-    extract::{Query as RQuery, State},
-    Json,
+    extract::{Path, State},
+    Extension, Json,
 };
-use booru_db::Query;
+use booru_db::{query::Item, Query, ID};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use crate::{
-    index::{TagDbCountIndex, TagDbIdIndex, TagIndex},
+    config::Config,
+    index::{queryable_ids, take_truncated, IdIndex, TagDbCountIndex, TagDbIdIndex, TagIndex},
+    post::TagCategory,
+    routes::{deserialize_limit, deserialize_page, log::Matched, total_pages, ApiError, Params},
     Db,
 };
 
@@ -29,10 +32,17 @@ pub struct GetTagsQuery {
     #[serde(default)]
     sort: TagsSort,
 
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_page")]
     page: usize,
-    #[serde(default = "tags_default_limit")]
+    #[serde(default = "tags_default_limit", deserialize_with = "deserialize_limit")]
     limit: usize,
+    // Shorthand for ANDing `count:>=N` into the query, `q` can then be left out.
+    #[serde(default)]
+    min_count: Option<u32>,
+    // Count only posts `/posts` shows by default, ie. not deleted or banned. Slower.
+    // Tags are still sorted and `min_count` filtered by their count over every post.
+    #[serde(default)]
+    visible_only: bool,
 }
 
 const fn tags_default_limit() -> usize {
@@ -45,25 +55,79 @@ pub struct TagsResponseTimings {
     sort: u64,
 }
 
+#[derive(Serialize)]
+pub struct TagsEntry {
+    name: Arc<str>,
+    count: u32,
+    category: TagCategory,
+}
+
 #[derive(Serialize)]
 pub struct TagsResponse {
-    tags: Vec<(Arc<str>, u32)>,
+    tags: Vec<TagsEntry>,
     matched: usize,
+    page: usize,
+    total_pages: usize,
+    limit: usize,
+    max_limit: usize,
+    // A name query hit `Config::name_query_limit` so not every matching tag was considered.
+    truncated: bool,
     timings: TagsResponseTimings,
 }
 
+// Posts tagged `name` that `/posts` shows by default, ie. not deleted or banned.
+// The tag is ANDed with `-status:deleted -status:banned` in the post db. Names the db would read
+// as another term, like `ratio:16:9` or `*_(cosplay)`, are counted from the tag index against
+// `hidden` instead, which is built on first use and kept for the rest of the request.
+fn visible_count(db: &Db, hidden: &mut Option<Vec<ID>>, name: &str) -> u32 {
+    let term = |text: &str, inverse| Query::new(Item::Single(text.to_string()), inverse);
+    let statuses = ["status:deleted", "status:banned"];
+    let literal = !name.contains(':') && !name.starts_with(['/', '*']) && !name.ends_with('*');
+    if literal {
+        let mut chain = vec![term(name, false)];
+        chain.extend(statuses.map(|status| term(status, true)));
+        let query = Query::new(Item::AndChain(chain), false);
+        return db.query(&query).map_or(0, |result| result.matched() as u32);
+    }
+
+    let hidden = hidden.get_or_insert_with(|| {
+        let chain = statuses.map(|status| term(status, false)).to_vec();
+        let query = Query::new(Item::OrChain(chain), false);
+        let result = db.query(&query).unwrap();
+        let mut ids = result.get(0, result.matched(), false);
+        ids.sort_unstable();
+        ids
+    });
+    let tag_index: &TagIndex = db.index().unwrap();
+    let Some(queryable) = tag_index.keys_index.items.get(name) else {
+        return 0;
+    };
+    queryable_ids(queryable)
+        .iter()
+        .filter(|id| hidden.binary_search(id).is_err())
+        .count() as u32
+}
+
 pub async fn get_tags(
     State(db): State<Arc<RwLock<Db>>>,
-    RQuery(GetTagsQuery {
+    State(config): State<Config>,
+    Params(GetTagsQuery {
         query,
         sort,
         page,
         limit,
-    }): RQuery<GetTagsQuery>,
-) -> Json<TagsResponse> {
+        min_count,
+        visible_only,
+    }): Params<GetTagsQuery>,
+) -> Result<(Extension<Matched>, Json<TagsResponse>), ApiError> {
     let mut timings = TagsResponseTimings::default();
 
-    let mut query = Query::parse(&query).unwrap(); // TODO
+    let text = match min_count {
+        Some(min_count) => format!("{query} count:>={min_count}"),
+        None => query,
+    };
+    let malformed = || ApiError::bad_query(&text, "malformed query");
+    let mut query = Query::parse(&text).map_err(|_| malformed())?;
     query.simplify();
 
     let db = db.read().await;
@@ -71,39 +135,357 @@ pub async fn get_tags(
     let tag_db = &tag_index.tag_db;
 
     let start_time = Instant::now();
-    let result = tag_db.query(&query).unwrap(); // TODO
+    take_truncated();
+    let result = tag_db.query(&query).map_err(|_| malformed())?;
+    let truncated = take_truncated();
     let elapsed = start_time.elapsed().as_nanos();
     timings.query = elapsed as u64;
 
-    let index = page * limit;
+    let limit = limit.min(config.tags_max_limit);
+    let matched = result.matched();
     let start_time = Instant::now();
-    let ids = match sort {
-        TagsSort::CountAsc | TagsSort::CountDesc => {
-            let reverse = matches!(sort, TagsSort::CountDesc);
-            let count_index: &TagDbCountIndex = tag_db.index().unwrap();
-            let sort = count_index.range_index.ids().iter().copied();
-            result.get_sorted(sort, index, limit, reverse)
-        }
+    let ids = match page.checked_mul(limit).filter(|&index| index < matched) {
+        None => Vec::new(),
+        Some(index) => match sort {
+            TagsSort::CountAsc | TagsSort::CountDesc => {
+                let reverse = matches!(sort, TagsSort::CountDesc);
+                let count_index: &TagDbCountIndex = tag_db.index().unwrap();
+                let sort = count_index.range_index.ids().iter().copied();
+                result.get_sorted(sort, index, limit, reverse)
+            }
+        },
     };
     let elapsed = start_time.elapsed().as_nanos();
     timings.sort = elapsed as u64;
 
     let id_index: &TagDbIdIndex = tag_db.index().unwrap();
+    let mut hidden = None;
     let tags: Vec<_> = ids
         .into_iter()
         .map(|id| {
             let name = id_index.id_to_name.get(&id).unwrap();
-            let count = tag_index.keys_index.items.get(name).unwrap().matched() as u32;
-            (name.clone(), count)
+            let count = match visible_only {
+                true => visible_count(&db, &mut hidden, name),
+                false => tag_index.keys_index.items.get(name).unwrap().matched() as u32,
+            };
+            TagsEntry {
+                name: name.clone(),
+                count,
+                category: tag_index.category(name),
+            }
         })
         .collect();
     drop(db);
 
-    let matched = result.matched();
     let response = TagsResponse {
         tags,
         matched,
+        page,
+        total_pages: total_pages(matched, limit),
+        limit,
+        max_limit: config.tags_max_limit,
+        truncated,
         timings,
     };
-    response.into()
+    Ok((Extension(Matched(matched)), response.into()))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetTagsAutocompleteQuery {
+    #[serde(default, alias = "q")]
+    prefix: String,
+    #[serde(default = "tags_autocomplete_default_limit")]
+    limit: usize,
+    // Skips rarer tags, the default only hides tags left behind with no posts.
+    #[serde(default = "tags_autocomplete_default_min_count")]
+    min_count: u32,
+}
+
+const fn tags_autocomplete_default_limit() -> usize {
+    10
+}
+
+const fn tags_autocomplete_default_min_count() -> u32 {
+    1
+}
+
+#[derive(Serialize)]
+pub struct AutocompleteTag {
+    name: Arc<str>,
+    count: u32,
+    category: TagCategory,
+}
+
+pub async fn get_tags_autocomplete(
+    State(db): State<Arc<RwLock<Db>>>,
+    State(config): State<Config>,
+    Params(GetTagsAutocompleteQuery {
+        prefix,
+        limit,
+        min_count,
+    }): Params<GetTagsAutocompleteQuery>,
+) -> Json<Vec<AutocompleteTag>> {
+    let prefix = prefix.trim();
+    if prefix.is_empty() {
+        return Vec::new().into();
+    }
+    // `/abv` is passed through so abbreviations complete too.
+    let text = match prefix.starts_with('/') {
+        true => prefix.to_string(),
+        false => format!("{prefix}*"),
+    };
+    let query = Query::new(
+        Item::AndChain(vec![
+            Query::new(Item::Single(text), false),
+            Query::new(Item::Single(format!("count:>={min_count}")), false),
+        ]),
+        false,
+    );
+
+    let db = db.read().await;
+    let tag_index: &TagIndex = db.index().unwrap();
+    let tag_db = &tag_index.tag_db;
+    let Ok(result) = tag_db.query(&query) else {
+        return Vec::new().into();
+    };
+    let count_index: &TagDbCountIndex = tag_db.index().unwrap();
+    let sort = count_index.range_index.ids().iter().copied();
+    let ids = result.get_sorted(sort, 0, limit.min(config.tags_max_limit), true);
+
+    let id_index: &TagDbIdIndex = tag_db.index().unwrap();
+    let tags: Vec<_> = ids
+        .into_iter()
+        .map(|id| {
+            let name = id_index.id_to_name.get(&id).unwrap();
+            let count = tag_index.keys_index.items.get(name).unwrap().matched() as u32;
+            AutocompleteTag {
+                name: name.clone(),
+                count,
+                category: tag_index.category(name),
+            }
+        })
+        .collect();
+    tags.into()
+}
+
+// Post ids returned as a sample of those carrying the tag.
+const TAG_DETAIL_SAMPLE: usize = 10;
+
+#[derive(Serialize)]
+pub struct TagDetail {
+    name: Arc<str>,
+    // Posts with the tag, including deleted and banned ones.
+    count: usize,
+    category: TagCategory,
+    // Id in the tag db, which should always have tags with posts.
+    tag_id: Option<ID>,
+    // Lowest post ids with the tag.
+    sample: Vec<u32>,
+}
+
+pub async fn get_tag_detail(
+    State(db): State<Arc<RwLock<Db>>>,
+    Path(name): Path<String>,
+) -> Result<Json<TagDetail>, ApiError> {
+    let db = db.read().await;
+    let tag_index: &TagIndex = db.index().unwrap();
+    // Tags whose posts were all retagged stay behind with no posts.
+    let tag = tag_index.keys_index.items.get_key_value(name.as_str());
+    let Some((name, posts)) = tag.filter(|(_, posts)| posts.matched() > 0) else {
+        return Err(ApiError::not_found("tag not found"));
+    };
+    let count = posts.matched();
+    let id_index: &TagDbIdIndex = tag_index.tag_db.index().unwrap();
+    let tag_id = id_index.name_to_id.get(name).copied();
+
+    // Read from the tag index directly, as a query term the name would be parsed.
+    let post_id_index: &IdIndex = db.index().unwrap();
+    let mut sample: Vec<_> = queryable_ids(posts)
+        .into_iter()
+        .filter_map(|id| post_id_index.id_to_post_id(id))
+        .collect();
+    if sample.len() > TAG_DETAIL_SAMPLE {
+        sample.select_nth_unstable(TAG_DETAIL_SAMPLE);
+        sample.truncate(TAG_DETAIL_SAMPLE);
+    }
+    sample.sort_unstable();
+
+    let response = TagDetail {
+        name: name.clone(),
+        count,
+        category: tag_index.category(name),
+        tag_id,
+        sample,
+    };
+    Ok(response.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_db,
+        post::{tests::post, Status},
+        BooruPost,
+    };
+
+    fn tagged(id: i32, tags: &[&str]) -> BooruPost {
+        BooruPost {
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+            ..post(id)
+        }
+    }
+
+    async fn tags(
+        db: &Arc<RwLock<Db>>,
+        config: Config,
+        query: &str,
+        min_count: Option<u32>,
+    ) -> TagsResponse {
+        let params = GetTagsQuery {
+            query: query.to_string(),
+            sort: TagsSort::CountDesc,
+            page: 0,
+            limit: 10,
+            min_count,
+            visible_only: false,
+        };
+        let result = get_tags(State(db.clone()), State(config), Params(params));
+        let (_, Json(response)) = result.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn name_query_limit_sets_truncated() {
+        let config = Config {
+            name_query_limit: 2,
+            ..Config::default()
+        };
+        let posts = vec![
+            tagged(1, &["cat", "cats", "catgirl"]),
+            tagged(2, &["cat", "dog"]),
+        ];
+        let db = load_db(posts, Default::default(), Default::default(), &config);
+        let db = Arc::new(RwLock::new(db));
+        let response = tags(&db, config, "cat*", None).await;
+        assert_eq!((response.matched, response.truncated), (2, true));
+        // Truncated before `count:` filtered them, fewer matches than the limit.
+        let response = tags(&db, config, "cat*", Some(2)).await;
+        assert!(response.truncated);
+        let response = tags(&db, config, "dog*", None).await;
+        assert_eq!((response.matched, response.truncated), (1, false));
+    }
+
+    #[tokio::test]
+    async fn min_count_excludes_rarer_tags() {
+        let config = Config::default();
+        let posts = vec![
+            tagged(1, &["cat", "cat_ears", "dog"]),
+            tagged(2, &["cat", "cat_ears"]),
+            tagged(3, &["cat"]),
+        ];
+        let db = load_db(posts, Default::default(), Default::default(), &config);
+        let db = Arc::new(RwLock::new(db));
+        let names = |response: TagsResponse| -> Vec<_> {
+            response.tags.into_iter().map(|tag| tag.name).collect()
+        };
+        let all = tags(&db, config, "", Some(1)).await;
+        assert_eq!(
+            names(all),
+            [Arc::from("cat"), "cat_ears".into(), "dog".into()]
+        );
+        let common = tags(&db, config, "", Some(2)).await;
+        assert_eq!(names(common), [Arc::from("cat"), "cat_ears".into()]);
+        let prefixed = tags(&db, config, "cat*", Some(3)).await;
+        assert_eq!(names(prefixed), [Arc::<str>::from("cat")]);
+    }
+
+    #[tokio::test]
+    async fn autocomplete_skips_rare_tags_sharing_a_prefix() {
+        let config = Config::default();
+        let posts = vec![
+            tagged(1, &["cat", "cat_ears", "catgirl"]),
+            tagged(2, &["cat", "cat_ears"]),
+            tagged(3, &["cat", "dog"]),
+        ];
+        let db = load_db(posts, Default::default(), Default::default(), &config);
+        let db = Arc::new(RwLock::new(db));
+        let complete = |prefix: &str, min_count: u32| {
+            let params = GetTagsAutocompleteQuery {
+                prefix: prefix.to_string(),
+                limit: 10,
+                min_count,
+            };
+            let result = get_tags_autocomplete(State(db.clone()), State(config), Params(params));
+            async move {
+                let Json(tags) = result.await;
+                tags.into_iter()
+                    .map(|tag| (tag.name, tag.count))
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(
+            complete("cat", 1).await,
+            [
+                (Arc::from("cat"), 3),
+                ("cat_ears".into(), 2),
+                ("catgirl".into(), 1)
+            ]
+        );
+        assert_eq!(
+            complete("cat", 2).await,
+            [(Arc::from("cat"), 3), ("cat_ears".into(), 2)]
+        );
+        assert_eq!(complete("cat", 3).await, [(Arc::from("cat"), 3)]);
+        assert!(complete("cat", 4).await.is_empty());
+        // The rare tag is still there for its own prefix.
+        assert_eq!(complete("catg", 1).await, [(Arc::from("catgirl"), 1)]);
+    }
+
+    #[tokio::test]
+    async fn tag_detail_reads_names_literally() {
+        let mut posts: Vec<_> = (1..=12).map(|id| tagged(id, &["ratio:16:9"])).collect();
+        posts.push(tagged(13, &["-_-", "*"]));
+        let db = load_db(
+            posts,
+            Default::default(),
+            Default::default(),
+            &Config::default(),
+        );
+        let db = Arc::new(RwLock::new(db));
+        let detail = |name: &str| get_tag_detail(State(db.clone()), Path(name.to_string()));
+
+        let Json(tag) = detail("ratio:16:9").await.unwrap();
+        assert_eq!(tag.count, 12);
+        assert_eq!(tag.sample, (1..=10).collect::<Vec<_>>());
+        for name in ["-_-", "*"] {
+            let Json(tag) = detail(name).await.unwrap();
+            assert_eq!((tag.count, tag.sample), (1, vec![13]));
+        }
+        assert!(detail("ratio").await.is_err());
+    }
+
+    #[test]
+    fn visible_counts_follow_status_changes() {
+        let tags = ["ratio:16:9", "*_(cosplay)", "-_-", "~tilde", "plain"];
+        let deleted = BooruPost {
+            status: Status::Deleted,
+            ..tagged(2, &tags)
+        };
+        let mut db = crate::tests::db(vec![tagged(1, &tags), deleted.clone(), tagged(3, &tags)]);
+        let counts = |db: &Db| -> Vec<_> {
+            let mut hidden = None;
+            tags.iter()
+                .map(|tag| visible_count(db, &mut hidden, tag))
+                .collect()
+        };
+        assert_eq!(counts(&db), [2; 5]);
+
+        let id_index: &IdIndex = db.index().unwrap();
+        let (first, second) = (id_index.post_id_to_id(1), id_index.post_id_to_id(2));
+        db.update(second.unwrap(), &deleted, &tagged(2, &tags));
+        assert_eq!(counts(&db), [3; 5]);
+        db.remove(first.unwrap(), &tagged(1, &tags));
+        assert_eq!(counts(&db), [2; 5]);
+    }
 }
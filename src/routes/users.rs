@@ -0,0 +1,120 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{extract::State, Json};
+use booru_db::Query;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    index::{UserDbCountIndex, UserDbIdIndex, UserIndex},
+    routes::{ApiError, Params},
+    Db,
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsersSort {
+    CountAsc,
+    #[default]
+    #[serde(alias = "count")]
+    CountDesc,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GetUsersQuery {
+    #[serde(default, alias = "q")]
+    query: String,
+    #[serde(default)]
+    sort: UsersSort,
+
+    #[serde(default)]
+    page: usize,
+    #[serde(default = "users_default_limit")]
+    limit: usize,
+}
+
+const fn users_default_limit() -> usize {
+    20
+}
+
+// Larger limits are clamped to this.
+const USERS_MAX_LIMIT: usize = 200;
+
+#[derive(Default, Serialize)]
+pub struct UsersResponseTimings {
+    query: u64,
+    sort: u64,
+}
+
+#[derive(Serialize)]
+pub struct UsersResponse {
+    users: Vec<(Arc<str>, u32)>,
+    matched: usize,
+    limit: usize,
+    max_limit: usize,
+    timings: UsersResponseTimings,
+}
+
+pub async fn get_users(
+    State(db): State<Arc<RwLock<Db>>>,
+    Params(GetUsersQuery {
+        query,
+        sort,
+        page,
+        limit,
+    }): Params<GetUsersQuery>,
+) -> Result<Json<UsersResponse>, ApiError> {
+    let mut timings = UsersResponseTimings::default();
+
+    let text = query;
+    let malformed = || ApiError::bad_query(&text, "malformed query");
+    let mut query = Query::parse(&text).map_err(|_| malformed())?;
+    query.simplify();
+
+    let db = db.read().await;
+    let user_index: &UserIndex = db.index().unwrap();
+    let user_db = &user_index.user_db;
+
+    let start_time = Instant::now();
+    let result = user_db.query(&query).map_err(|_| malformed())?;
+    let elapsed = start_time.elapsed().as_nanos();
+    timings.query = elapsed as u64;
+
+    let limit = limit.min(USERS_MAX_LIMIT);
+    let matched = result.matched();
+    let start_time = Instant::now();
+    let ids = match page.checked_mul(limit).filter(|&index| index < matched) {
+        None => Vec::new(),
+        Some(index) => match sort {
+            UsersSort::CountAsc | UsersSort::CountDesc => {
+                let reverse = matches!(sort, UsersSort::CountDesc);
+                let count_index: &UserDbCountIndex = user_db.index().unwrap();
+                let sort = count_index.range_index.ids().iter().copied();
+                result.get_sorted(sort, index, limit, reverse)
+            }
+        },
+    };
+    let elapsed = start_time.elapsed().as_nanos();
+    timings.sort = elapsed as u64;
+
+    let id_index: &UserDbIdIndex = user_db.index().unwrap();
+    let users: Vec<_> = ids
+        .into_iter()
+        .map(|id| {
+            let user_id = id_index.id_to_user_id.get(&id).unwrap();
+            let name = user_index.names.get(user_id).unwrap();
+            let count = user_index.upload_counts.get(user_id).copied().unwrap_or(0);
+            (name.clone(), count)
+        })
+        .collect();
+    drop(db);
+
+    let response = UsersResponse {
+        users,
+        matched,
+        limit,
+        max_limit: USERS_MAX_LIMIT,
+        timings,
+    };
+    Ok(response.into())
+}
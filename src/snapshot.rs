@@ -0,0 +1,78 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{intern, post::BooruPost};
+
+// Bump whenever the serialized shape of `BooruPost` changes.
+const VERSION: u32 = 6;
+
+// A snapshot is a version line followed by one JSON encoded `BooruPost` per line,
+// stored in the order the posts were indexed so ids are assigned identically on reload.
+pub fn read_snapshot(path: &Path) -> io::Result<Vec<BooruPost>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let Some(version) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let version: u32 = serde_json::from_str(&version?)?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("snapshot version {version} does not match {VERSION}"),
+        ));
+    }
+    // Serde allocates every tag on its own, share them like posts read from the database.
+    let tag_names = intern::tag_names();
+    lines
+        .map(|line| {
+            let mut post: BooruPost = serde_json::from_str(&line?)?;
+            for tag in &mut post.tags {
+                *tag = tag_names.intern(tag);
+            }
+            Ok(post)
+        })
+        .collect()
+}
+
+pub struct SnapshotWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SnapshotWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        // Write next to the real file and rename on finish so a crash never leaves a partial snapshot.
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(writer, "{VERSION}")?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            writer,
+        })
+    }
+
+    pub fn write(&mut self, post: &BooruPost) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, post)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.tmp_path, &self.path)
+    }
+}
+
+pub fn write_snapshot<'a>(
+    path: &Path,
+    posts: impl Iterator<Item = &'a BooruPost>,
+) -> io::Result<()> {
+    let mut writer = SnapshotWriter::create(path)?;
+    for post in posts {
+        writer.write(post)?;
+    }
+    writer.finish()
+}
@@ -1,11 +1,23 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use serde::Deserialize;
-use sqlx::{postgres::PgListener, Executor};
-use tokio::sync::RwLock;
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgListener, PgNotification},
+    Executor,
+};
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+use tracing::{debug, info, warn};
 
 use crate::{
-    index::IdIndex,
+    cache::QueryCache,
+    index::{IdIndex, PostIndex, UpdatedAtIndex},
     post::{BooruPost, RawBooruPost},
     Db,
 };
@@ -39,58 +51,440 @@ pub async fn create_listener(uri: &str, pool: &sqlx::PgPool) -> PgListener {
     )
     .await
     .unwrap();
-    let mut listener = PgListener::connect(uri).await.unwrap();
+    connect_listener(uri).await.unwrap()
+}
+
+async fn connect_listener(uri: &str) -> sqlx::Result<PgListener> {
+    let mut listener = PgListener::connect(uri).await?;
     listener
         .listen_all(vec![
             "public_posts_insert",
             "public_posts_update",
             "public_posts_delete",
         ])
-        .await
-        .unwrap();
-    listener
+        .await?;
+    Ok(listener)
 }
 
-pub async fn handle_listener(db: Arc<RwLock<Db>>, mut pg_listener: PgListener) {
-    #[derive(Deserialize)]
-    struct Update {
-        old: RawBooruPost,
-        new: RawBooruPost,
-    }
-    while let Ok(notif) = pg_listener.recv().await {
-        let channel = notif.channel();
-        let payload = notif.payload();
-        let start_time = Instant::now();
-        match channel {
-            "public_posts_update" => {
-                let data: Update = serde_json::from_str(payload).unwrap();
-                let old: BooruPost = data.old.into();
-                let new = data.new.into();
-                let mut db = db.write().await;
-                let id_index: &IdIndex = db.index().unwrap();
-                let id = id_index.post_id_to_id(old.id).unwrap();
+// How long to wait for further notifications once shutdown was requested.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+// Notifications arriving within this of each other are applied under a single write lock.
+const BATCH_TIMEOUT: Duration = Duration::from_millis(5);
+const BATCH_SIZE: usize = 1024;
+// Wait between attempts when reconnecting the listener fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Held by sync whenever it applies changes, so `/admin/reload` can keep it from touching the Db
+// between swapping in a new one and catching that up.
+#[derive(Clone, Default)]
+pub struct SyncLock(Arc<Mutex<()>>);
+
+impl SyncLock {
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.0.lock().await
+    }
+}
+
+// Sent to `/live` subscribers for every change applied.
+#[derive(Clone, Serialize)]
+pub struct LiveEvent {
+    op: &'static str,
+    post_id: u32,
+}
+
+enum Change {
+    Update(BooruPost, BooruPost),
+    Insert(BooruPost),
+    Delete(BooruPost),
+}
+
+impl Change {
+    // None for payloads that don't deserialize or whose rows don't fit, eg. a null or overflowing
+    // column. They are logged and skipped so one bad row doesn't stop syncing.
+    fn parse(notif: &PgNotification) -> Option<Self> {
+        Self::from_payload(notif.channel(), notif.payload())
+    }
+
+    fn from_payload(channel: &str, payload: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct Update {
+            old: RawBooruPost,
+            new: RawBooruPost,
+        }
+        let change = match channel {
+            "public_posts_update" => serde_json::from_str(payload).map(|data: Update| {
+                match (BooruPost::from_row(data.old), BooruPost::from_row(data.new)) {
+                    (Some(old), Some(new)) => Some(Self::Update(old, new)),
+                    // A row that didn't fit was never indexed.
+                    (None, Some(new)) => Some(Self::Insert(new)),
+                    (Some(old), None) => Some(Self::Delete(old)),
+                    (None, None) => None,
+                }
+            }),
+            "public_posts_insert" => serde_json::from_str(payload)
+                .map(|raw: RawBooruPost| BooruPost::from_row(raw).map(Self::Insert)),
+            "public_posts_delete" => serde_json::from_str(payload)
+                .map(|raw: RawBooruPost| BooruPost::from_row(raw).map(Self::Delete)),
+            _ => {
+                unreachable!()
+            }
+        };
+        match change {
+            Ok(change) => change,
+            Err(error) => {
+                warn!(%error, channel, payload, "skipping malformed sync notification");
+                None
+            }
+        }
+    }
+
+    fn updated_at(&self) -> Option<NaiveDateTime> {
+        match self {
+            Self::Update(_, post) | Self::Insert(post) => Some(post.updated_at),
+            Self::Delete(_) => None,
+        }
+    }
+
+    // Changes are applied to the post as indexed rather than the old row they carry. After a
+    // reconnect or reload the Db can already be ahead of a notification, so an update can find
+    // its post missing or changed and a delete can find its post gone.
+    fn apply(self, db: &mut Db) -> Option<LiveEvent> {
+        let (post_id, new) = match self {
+            Self::Update(old, new) => (old.id, Some(new)),
+            Self::Insert(new) => (new.id, Some(new)),
+            Self::Delete(old) => (old.id, None),
+        };
+        let id_index: &IdIndex = db.index().unwrap();
+        let post_index: &PostIndex = db.index().unwrap();
+        let indexed = id_index
+            .post_id_to_id(post_id)
+            .and_then(|id| Some((id, post_index.get(id)?.clone())));
+        let (op, post_id) = match (indexed, new) {
+            (Some((id, old)), Some(new)) => {
                 db.update(id, &old, &new);
+                ("update", new.id)
             }
-            "public_posts_insert" => {
-                let raw: RawBooruPost = serde_json::from_str(payload).unwrap();
-                let post = raw.into();
-                let mut db = db.write().await;
-                let id = db.next_id();
-                db.insert(id, &post);
+            (None, Some(new)) => {
+                let id_index: &mut IdIndex = db.index_mut().unwrap();
+                let id = id_index.take_free_id().unwrap_or_else(|| db.next_id());
+                db.insert(id, &new);
+                ("insert", new.id)
             }
-            "public_posts_delete" => {
-                let raw: RawBooruPost = serde_json::from_str(payload).unwrap();
-                let post: BooruPost = raw.into();
-                let mut db = db.write().await;
-                let id_index: &IdIndex = db.index().unwrap();
-                let id = id_index.post_id_to_id(post.id).unwrap();
-                db.remove(id, &post);
+            (Some((id, old)), None) => {
+                db.remove(id, &old);
+                ("delete", old.id)
             }
-            _ => {
-                unreachable!()
+            (None, None) => return None,
+        };
+        Some(LiveEvent { op, post_id })
+    }
+}
+
+// Collect notifications until none arrive within `timeout` or the batch is full.
+// Returns false if the connection was lost, notifications sent meanwhile are gone.
+async fn fill_batch(
+    pg_listener: &mut PgListener,
+    batch: &mut Vec<Change>,
+    timeout: Duration,
+) -> bool {
+    while batch.len() < BATCH_SIZE {
+        match tokio::time::timeout(timeout, pg_listener.try_recv()).await {
+            Ok(Ok(Some(notif))) => batch.extend(Change::parse(&notif)),
+            Ok(Ok(None)) => return false,
+            _ => break,
+        }
+    }
+    true
+}
+
+// Newest `updated_at` in the Db, truncated to milliseconds like the index.
+pub fn last_updated_at(db: &Db) -> Option<NaiveDateTime> {
+    let updated_at_index: &UpdatedAtIndex = db.index().unwrap();
+    let range_index = &updated_at_index.range_index;
+    let last = range_index.ids().last()?;
+    NaiveDateTime::from_timestamp_millis(*range_index.id_values().get(last)?)
+}
+
+// Refetches every row updated after `last_seen`, a page at a time, to make up for
+// notifications missed while disconnected. Rows deleted meanwhile are not noticed.
+pub async fn catch_up(
+    db: &RwLock<Db>,
+    query_cache: &QueryCache,
+    sync_lag: &AtomicI64,
+    live: &broadcast::Sender<LiveEvent>,
+    pool: &sqlx::PgPool,
+    last_seen: NaiveDateTime,
+) -> sqlx::Result<usize> {
+    let mut cursor = (last_seen, 0);
+    let mut caught_up = 0;
+    loop {
+        let rows = sqlx::query_as::<_, RawBooruPost>(
+            "SELECT * FROM posts WHERE (updated_at, id) > ($1, $2) ORDER BY updated_at, id LIMIT $3",
+        )
+        .bind(cursor.0)
+        .bind(cursor.1)
+        .bind(BATCH_SIZE as i64)
+        .fetch_all(pool)
+        .await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        cursor = (last.updated_at, last.id);
+        let page = rows.len();
+
+        // Rows already indexed are updated, see `Change::apply`.
+        let mut batch: Vec<_> = rows
+            .into_iter()
+            .filter_map(BooruPost::from_row)
+            .map(Change::Insert)
+            .collect();
+        apply_batch(db, query_cache, sync_lag, live, &mut batch).await;
+        caught_up += page;
+        if page < BATCH_SIZE {
+            break;
+        }
+    }
+    Ok(caught_up)
+}
+
+// Changes are applied in the order they were received, an update may depend on a prior insert.
+async fn apply_batch(
+    db: &RwLock<Db>,
+    query_cache: &QueryCache,
+    sync_lag: &AtomicI64,
+    live: &broadcast::Sender<LiveEvent>,
+    batch: &mut Vec<Change>,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    let changes = batch.len();
+    let updated_at = batch.iter().rev().find_map(Change::updated_at);
+    let start_time = Instant::now();
+    let mut db = db.write().await;
+    let events: Vec<_> = batch
+        .drain(..)
+        .filter_map(|change| change.apply(&mut db))
+        .collect();
+    // Before the write lock is released, a reader can't cache a result of the old Db under the
+    // new generation.
+    query_cache.invalidate();
+    drop(db);
+    // Only fails without subscribers. Slow ones lag behind instead of holding up sync.
+    for event in events {
+        let _ = live.send(event);
+    }
+    // Time from the row being written upstream to it being searchable here.
+    if let Some(updated_at) = updated_at {
+        let lag = Utc::now().naive_utc() - updated_at;
+        sync_lag.store(lag.num_milliseconds(), Ordering::Relaxed);
+    }
+    let elapsed = start_time.elapsed().as_nanos();
+    debug!(changes, "{:.3}ms", elapsed as f64 / 1000.0 / 1000.0);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_listener(
+    db: Arc<RwLock<Db>>,
+    query_cache: Arc<QueryCache>,
+    sync_lag: Arc<AtomicI64>,
+    live: broadcast::Sender<LiveEvent>,
+    pool: sqlx::PgPool,
+    uri: String,
+    sync_lock: SyncLock,
+    mut pg_listener: PgListener,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut last_seen = last_updated_at(&*db.read().await);
+    // Set once the listener reconnected, rows updated after it may have been missed.
+    // It stays put until a catch up succeeds so a failed one is retried from the same point.
+    let mut missed_since = None;
+    let mut batch = Vec::new();
+    loop {
+        if let Some(since) = missed_since {
+            let caught_up = {
+                let _sync = sync_lock.lock().await;
+                catch_up(&db, &query_cache, &sync_lag, &live, &pool, since).await
+            };
+            match caught_up {
+                Ok(caught_up) => {
+                    info!(caught_up, "sync caught up");
+                    missed_since = None;
+                    last_seen = last_updated_at(&*db.read().await).max(last_seen);
+                }
+                Err(error) => {
+                    warn!(%error, "sync catch up failed, retrying");
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                        _ = shutdown.changed() => break,
+                    }
+                }
             }
+        }
+
+        let connected = tokio::select! {
+            notif = pg_listener.try_recv() => match notif {
+                Ok(Some(notif)) => {
+                    batch.extend(Change::parse(&notif));
+                    fill_batch(&mut pg_listener, &mut batch, BATCH_TIMEOUT).await
+                }
+                Ok(None) => false,
+                Err(error) => {
+                    warn!(%error, "sync listener failed");
+                    false
+                }
+            },
+            _ = shutdown.changed() => break,
         };
-        let elapsed = start_time.elapsed().as_nanos();
-        println!("{channel}: {:.3}ms", elapsed as f64 / 1000.0 / 1000.0);
+        last_seen = batch
+            .iter()
+            .filter_map(Change::updated_at)
+            .max()
+            .max(last_seen);
+        {
+            let _sync = sync_lock.lock().await;
+            apply_batch(&db, &query_cache, &sync_lag, &live, &mut batch).await;
+        }
+
+        // Catch up only once listening again, so every change after the catch up's query
+        // is also notified.
+        if !connected {
+            warn!("sync listener lost its connection, reconnecting");
+            pg_listener = loop {
+                match connect_listener(&uri).await {
+                    Ok(pg_listener) => break pg_listener,
+                    Err(error) => warn!(%error, "sync listener failed to reconnect"),
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                    _ = shutdown.changed() => return,
+                }
+            };
+            missed_since = missed_since.or(last_seen);
+        }
+    }
+
+    // Apply whatever postgres already sent before exiting.
+    let mut drained = 0;
+    loop {
+        fill_batch(&mut pg_listener, &mut batch, DRAIN_TIMEOUT).await;
+        if batch.is_empty() {
+            break;
+        }
+        drained += batch.len();
+        let _sync = sync_lock.lock().await;
+        apply_batch(&db, &query_cache, &sync_lag, &live, &mut batch).await;
+    }
+    info!(drained, "sync stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        post::tests::post,
+        tests::{db, search},
+    };
+
+    // A `posts` row as the notify triggers send it.
+    fn row(id: i32, image_width: i32) -> serde_json::Value {
+        json!({
+            "id": id,
+            "parent_id": null,
+            "pixiv_id": null,
+            "uploader_id": 1,
+            "approver_id": null,
+            "is_banned": false,
+            "is_deleted": false,
+            "is_flagged": false,
+            "is_pending": false,
+            "created_at": "2024-01-01T00:00:00",
+            "updated_at": "2024-01-01T00:00:00",
+            "fav_count": 0,
+            "up_score": 0,
+            "down_score": 0,
+            "source": "",
+            "image_width": image_width,
+            "image_height": 1000,
+            "file_ext": "jpg",
+            "file_size": 1000,
+            "rating": "g",
+            "is_note_locked": false,
+            "pool_string": "",
+            "fav_string": "",
+            "tag_string": "a b",
+            "tag_count_general": 2,
+            "tag_count_artist": 0,
+            "tag_count_character": 0,
+            "tag_count_copyright": 0,
+            "tag_count_meta": 0,
+            "bit_flags": 0,
+            "last_noted_at": null,
+            "last_commented_at": null,
+        })
+    }
+
+    fn op(channel: &str, payload: &str) -> Option<&'static str> {
+        Change::from_payload(channel, payload).map(|change| match change {
+            Change::Update(..) => "update",
+            Change::Insert(_) => "insert",
+            Change::Delete(_) => "delete",
+        })
+    }
+
+    #[test]
+    fn malformed_payloads_are_skipped() {
+        let insert = "public_posts_insert";
+        assert_eq!(op(insert, &row(1, 1000).to_string()), Some("insert"));
+        assert_eq!(op(insert, ""), None);
+        assert_eq!(op(insert, "{\"id\": 1"), None);
+        assert_eq!(op(insert, "{\"id\": 1}"), None);
+        assert_eq!(op(insert, "[]"), None);
+        // Deserializes, but the row doesn't fit.
+        assert_eq!(op(insert, &row(1, 70_000).to_string()), None);
+        assert_eq!(op("public_posts_delete", "null"), None);
+
+        let update = "public_posts_update";
+        let changed = |old, new| json!({ "old": old, "new": new }).to_string();
+        assert_eq!(
+            op(update, &changed(row(1, 1000), row(1, 900))),
+            Some("update")
+        );
+        assert_eq!(
+            op(update, &changed(row(1, 70_000), row(1, 900))),
+            Some("insert")
+        );
+        assert_eq!(
+            op(update, &changed(row(1, 1000), row(1, 70_000))),
+            Some("delete")
+        );
+        assert_eq!(
+            op(update, &json!({ "new": row(1, 1000) }).to_string()),
+            None
+        );
+    }
+
+    #[test]
+    fn deleted_ids_are_reused() {
+        let mut db = db(vec![post(1), post(2)]);
+        let id_index: &IdIndex = db.index().unwrap();
+        let id = id_index.post_id_to_id(1).unwrap();
+        Change::Delete(post(1)).apply(&mut db);
+        let id_index: &IdIndex = db.index().unwrap();
+        assert_eq!(id_index.free_ids(), 1);
+
+        Change::Insert(post(3)).apply(&mut db);
+        let id_index: &IdIndex = db.index().unwrap();
+        assert_eq!(id_index.post_id_to_id(3), Some(id));
+        assert_eq!(id_index.free_ids(), 0);
+        // Nothing left to reuse, the next insert gets a new id.
+        Change::Insert(post(4)).apply(&mut db);
+        let id_index: &IdIndex = db.index().unwrap();
+        let new_id = id_index.post_id_to_id(4).unwrap();
+        assert_ne!(new_id, id);
+        assert_ne!(new_id, id_index.post_id_to_id(2).unwrap());
+        assert_eq!(search(&db, "id:any"), [2, 3, 4]);
     }
 }